@@ -51,5 +51,5 @@ pub fn ansi(style: AnsiStyle, color: AnsiColor) -> String {
 }
 
 pub fn style(style: AnsiStyle, color: AnsiColor, text: &str) -> String {
-    return format!("{}{}{}", ansi(style, color), text, ansi(AnsiStyle::Reset, AnsiColor::Default));
+    format!("{}{}{}", ansi(style, color), text, ansi(AnsiStyle::Reset, AnsiColor::Default))
 }
@@ -0,0 +1,74 @@
+// Block IDs name the files holding blocks in the data nodes' data directories, so an ID coming in over the wire is
+// parsed into a BlockId before it gets anywhere near a path; only "block_" followed by a lowercase hyphenated UUID is one
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+const PREFIX: &str = "block_";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(String);
+
+impl BlockId {
+    // generate returns a fresh block ID for a new block
+    pub fn generate() -> Self {
+        BlockId(format!("{}{}", PREFIX, Uuid::new_v4()))
+    }
+
+    pub fn parse(id: &str) -> Result<Self, InvalidBlockId> {
+        let well_formed = id.strip_prefix(PREFIX)
+            .and_then(|uuid| Uuid::try_parse(uuid).ok().filter(|parsed| parsed.hyphenated().to_string() == uuid))
+            .is_some();
+        if well_formed {
+            Ok(BlockId(id.to_string()))
+        } else {
+            Err(InvalidBlockId(id.to_string()))
+        }
+    }
+
+    // parse_all parses a list of block IDs, failing on the first one that is not well formed
+    pub fn parse_all(ids: &[String]) -> Result<Vec<Self>, InvalidBlockId> {
+        ids.iter().map(|id| BlockId::parse(id)).collect()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for BlockId {
+    type Err = InvalidBlockId;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        BlockId::parse(id)
+    }
+}
+
+impl From<BlockId> for String {
+    fn from(id: BlockId) -> Self {
+        id.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidBlockId(pub String);
+
+impl fmt::Display for InvalidBlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not a valid block ID", self.0)
+    }
+}
+
+impl std::error::Error for InvalidBlockId {}
+
+impl From<InvalidBlockId> for tonic::Status {
+    fn from(e: InvalidBlockId) -> Self {
+        tonic::Status::invalid_argument(e.to_string())
+    }
+}
@@ -0,0 +1,28 @@
+// CRC32C checksums over fixed-size chunks of a block, computed by the data node that stores the block
+// and checked again by whoever reads it, so corruption on disk or on the wire is caught at either end
+
+// every chunk but the last of a block is this many bytes long
+pub const BYTES_PER_CHECKSUM: usize = 512;
+
+// streamed blocks move in packets of this many bytes, a whole number of chunks so every packet carries the checksums of its own bytes
+pub const PACKET_SIZE: usize = 128 * BYTES_PER_CHECKSUM;
+
+// chunk_checksums returns the CRC32C of each BYTES_PER_CHECKSUM chunk of data
+pub fn chunk_checksums(data: &[u8]) -> Vec<u32> {
+    data.chunks(BYTES_PER_CHECKSUM).map(crc32c::crc32c).collect()
+}
+
+// verify checks data against its chunk checksums and returns the index of the first chunk that does not match,
+// a checksum list of the wrong length fails at the first chunk it does not cover
+pub fn verify(data: &[u8], checksums: &[u32]) -> Result<(), usize> {
+    let chunks = data.len().div_ceil(BYTES_PER_CHECKSUM);
+    for (index, chunk) in data.chunks(BYTES_PER_CHECKSUM).enumerate() {
+        if checksums.get(index) != Some(&crc32c::crc32c(chunk)) {
+            return Err(index);
+        }
+    }
+    if checksums.len() != chunks {
+        return Err(chunks.min(checksums.len()));
+    }
+    Ok(())
+}
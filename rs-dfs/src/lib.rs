@@ -1,16 +1,21 @@
 // export ansi module
 pub mod ansi;
+pub mod block_id;
+pub mod checksum;
+pub mod pipeline;
+pub mod read;
 
 pub use ansi::{AnsiColor, AnsiStyle, ansi};
+pub use block_id::{BlockId, InvalidBlockId};
 
 use serde::{Serialize, Deserialize};
 
 
-mod namenode {
+pub mod namenode {
     tonic::include_proto!("namenode");
 }
 
-mod datanode {
+pub mod datanode {
     tonic::include_proto!("datanode");
 }
 
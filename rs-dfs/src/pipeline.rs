@@ -0,0 +1,17 @@
+// acks of a write pipeline, gathered by whoever pushes a block to its head and passed back up by every data node in it
+use tonic::Status;
+use crate::datanode::{PipelineAck, PutDataResponse};
+
+// pipeline_acks turns the answer of the head of a write pipeline into acks: its own followed by the ones it passed back,
+// or a failed one for it alone if it failed, the data nodes behind it were never reached
+pub fn pipeline_acks(head: &str, outcome: Result<PutDataResponse, Status>) -> Vec<PipelineAck> {
+    match outcome {
+        Ok(response) if response.success => {
+            let mut acks = vec![PipelineAck { node: head.to_string(), success: true, message: String::new() }];
+            acks.extend(response.acks);
+            acks
+        }
+        Ok(_) => vec![PipelineAck { node: head.to_string(), success: false, message: "did not acknowledge the block".to_string() }],
+        Err(e) => vec![PipelineAck { node: head.to_string(), success: false, message: e.message().to_string() }],
+    }
+}
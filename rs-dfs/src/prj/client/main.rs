@@ -1,5 +1,6 @@
 
 use rs_dfs::ansi::{AnsiColor, AnsiStyle, style};
+use rs_dfs::read::fetch_range;
 use std::io::{self, Write};
 use termion::raw::IntoRawMode;
 use std::fs;
@@ -10,15 +11,12 @@ use crossterm::{
     event::{self, Event, KeyCode, KeyEvent},
 };
 use std::sync::{Arc, Mutex};
-use namenode::name_node_client::NameNodeClient;
-use namenode::{ReadFileRequest, ReadFileResponse, WriteFileRequest, WriteFileResponse, BlockSizeRequest, BlockSizeResponse, AssignBlocksForFileRequest, AssignBlocksForFileResponse};
-mod namenode{
-    tonic::include_proto!("namenode");
-}
+use rs_dfs::namenode::name_node_client::NameNodeClient;
+use rs_dfs::namenode::{WriteFileRequest, GetBlockLocationsRequest, LocatedBlock, ListDirectoryRequest, MkdirsRequest, DeleteRequest, DeleteFileRequest, RenameRequest, GetFileInfoRequest};
 
 const DATA_DIR: &str = ".data";
 const HISTORY_FILE: &str = ".history";
-const CURSOR_OFFSET: usize = 6;
+const NAMENODE_ADDR: &str = "http://localhost:50051";
 
 macro_rules! rprintln {
     () => {
@@ -42,13 +40,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", style(AnsiStyle::BoldHighIntensityText, AnsiColor::Green, "Distributed File System Client starting...\n"));
     fs::create_dir_all(DATA_DIR).unwrap();
     let history_path = Path::new(DATA_DIR).join(HISTORY_FILE);
-    let history_file = fs::OpenOptions::new()
+    // make sure the history file exists before the first command is read
+    fs::OpenOptions::new()
         .append(true)
         .create(true)
         .open(&history_path)
         .unwrap();
     let mut input = String::new();
-    let mut history = load_history(&history_path);
     let mut graceful_exit = 0;
     let default_panic = std::panic::take_hook();
     {   // extra scope to drop stdout before exiting
@@ -57,13 +55,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     std::panic::set_hook(Box::new(move |info| {
         let mut stdout = cls_stdout.lock().unwrap();
         stdout.suspend_raw_mode().unwrap();
-        stdout.write(b"\n").unwrap();
+        stdout.write_all(b"\n").unwrap();
         default_panic(info);
     }));
     let mut history_index = 0;
     let mut command = String::new();
     loop {
-            history = load_history(&history_path);
+            let history = load_history(&history_path);
             print!(
                 "\r{} {} ",
                 style(AnsiStyle::BoldHighIntensityText, AnsiColor::Cyan, "dfsc"),
@@ -73,46 +71,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             // Capture input one key at a time
             loop {
-                if let Event::Key(KeyEvent { code, modifiers, kind, state }) = event::read()? {
+                if let Event::Key(KeyEvent { code, modifiers, .. }) = event::read()? {
                     match code {
                         KeyCode::Char(c) if modifiers.contains(event::KeyModifiers::CONTROL) && (c == 'c' || c == 'd' || c == 'z') => {
                             stdout.lock().unwrap().suspend_raw_mode().unwrap();
                             graceful_exit = 1;
                             break;
                         }
-                        KeyCode::Char(c) => {
-                            if c.is_ascii() {
-                                input.push(c);
-                                print!("{}", c);
-                                stdout.lock().unwrap().flush().unwrap();
-                            }
+                        KeyCode::Char(c) if c.is_ascii() => {
+                            input.push(c);
+                            print!("{}", c);
+                            stdout.lock().unwrap().flush().unwrap();
                         }
-                        KeyCode::Backspace => {
-                            if !input.is_empty() {
-                                input.pop();
-                                print!("\x08 \x08"); // \x08 is the backspace character, two times because we want to move the cursor back twice
-                                stdout.lock().unwrap().flush().unwrap();
-                            }
+                        KeyCode::Backspace if !input.is_empty() => {
+                            input.pop();
+                            print!("\x08 \x08"); // \x08 is the backspace character, two times because we want to move the cursor back twice
+                            stdout.lock().unwrap().flush().unwrap();
                         }
-                        KeyCode::Delete => { // Delete is the same as backspace  but in opposite direction
-                            if !input.is_empty() {
-                                input.remove(0);
-                                print!("\x7f");
-                                stdout.lock().unwrap().flush().unwrap();
-                            }
+                        KeyCode::Delete if !input.is_empty() => { // Delete is the same as backspace  but in opposite direction
+                            input.remove(0);
+                            print!("\x7f");
+                            stdout.lock().unwrap().flush().unwrap();
                         }
-                        KeyCode::Left => {
-                            if !input.is_empty() {
-                                print!("\x1b[D");
-                                stdout.lock().unwrap().flush().unwrap();
-                            }
+                        KeyCode::Left if !input.is_empty() => {
+                            print!("\x1b[D");
+                            stdout.lock().unwrap().flush().unwrap();
                         }
-                        KeyCode::Right => {
-                            if !input.is_empty() {
-                                // print!("\x08");
-                                print!("\x1b[C");
-                                stdout.lock().unwrap().flush().unwrap();
-                            }
+                        KeyCode::Right if !input.is_empty() => {
+                            // print!("\x08");
+                            print!("\x1b[C");
+                            stdout.lock().unwrap().flush().unwrap();
                         }
                         KeyCode::Up => { // scroll through history
                             if history_index > 0 {
@@ -170,9 +158,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 stdout.lock().unwrap().suspend_raw_mode().unwrap();
                 graceful_exit = 0;
                 break;
-            } else if command != "" {
+            } else if !command.is_empty() {
                 rprintln!("command: {}", command);
-                parse_command(&command).await?;
+                if let Err(e) = parse_command(&command).await {
+                    rprintln!("{}", style(AnsiStyle::BoldHighIntensityText, AnsiColor::Red, &format!("Error: {}", e)));
+                }
             }
 
             command.clear();
@@ -202,33 +192,160 @@ async fn parse_command(command: &str) -> Result<(), Box<dyn std::error::Error>>
     match command {
         "exit" => exit(0),
         "ls" => {
-            // let mut client = NameNodeClient::connect("http://localhost:50051").await?;
-            // let request = tonic::Request::new(ListDirectoryRequest {
-            //     path: "/".to_string(),
-            // });
-            // let response = client.list_directory(request).await?;
-            // println!("Response: {:?}", response);
-            rprintln!("ls");
+            let path = args.first().unwrap_or(&"/").to_string();
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let request = tonic::Request::new(ListDirectoryRequest { path });
+            let response = client.list_directory(request).await?.into_inner();
+            for entry in response.entries {
+                if entry.is_directory {
+                    rprintln!("{}/", style(AnsiStyle::BoldText, AnsiColor::Blue, &entry.name));
+                } else {
+                    rprintln!("{}\t{}", entry.name, entry.length);
+                }
+            }
+        },
+        "mkdir" => {
+            if args.is_empty() {
+                rprintln!("Usage: mkdir <path>");
+                return Ok(());
+            }
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let request = tonic::Request::new(MkdirsRequest { path: args[0].to_string() });
+            let response = client.mkdirs(request).await?;
+            rprintln!("Response: {:?}", response);
+        },
+        "rm" => {
+            let recursive = args.first() == Some(&"-r");
+            let paths = if recursive { &args[1..] } else { args };
+            if paths.is_empty() {
+                rprintln!("Usage: rm [-r] <path>");
+                return Ok(());
+            }
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let response = if recursive {
+                let request = tonic::Request::new(DeleteRequest { path: paths[0].to_string(), recursive });
+                client.delete(request).await?.into_inner().success
+            } else {
+                let request = tonic::Request::new(DeleteFileRequest { filename: paths[0].to_string() });
+                client.delete_file(request).await?.into_inner().success
+            };
+            rprintln!("Response: {:?}", response);
+        },
+        "mv" => {
+            let overwrite = args.first() == Some(&"-f");
+            let paths = if overwrite { &args[1..] } else { args };
+            if paths.len() < 2 {
+                rprintln!("Usage: mv [-f] <src> <dst>");
+                return Ok(());
+            }
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let request = tonic::Request::new(RenameRequest {
+                src: paths[0].to_string(),
+                dst: paths[1].to_string(),
+                overwrite,
+            });
+            let response = client.rename(request).await?;
+            rprintln!("Response: {:?}", response);
+        },
+        "stat" => {
+            if args.is_empty() {
+                rprintln!("Usage: stat <path>");
+                return Ok(());
+            }
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let request = tonic::Request::new(GetFileInfoRequest { path: args[0].to_string() });
+            let info = client.get_file_info(request).await?.into_inner().info.unwrap_or_default();
+            rprintln!("Path: {}", info.path);
+            if info.is_directory {
+                rprintln!("Type: directory");
+            } else {
+                rprintln!("Type: file");
+                rprintln!("Length: {}", info.length);
+                rprintln!("Block size: {}", info.block_size);
+                rprintln!("Blocks: {}", info.block_count);
+                rprintln!("Replication: {}", info.replication);
+                rprintln!("Accessed: {}", info.atime);
+            }
+            rprintln!("Modified: {}", info.mtime);
         },
         "put" => {
-            let filename = args[1].to_string();
-            let data = args[2].to_string();
+            if args.len() < 2 {
+                rprintln!("Usage: put <filename> <data>");
+                return Ok(());
+            }
+            let filename = args[0].to_string();
+            let data = args[1..].join(" ");
             rprintln!("put {}", filename);
-            let mut client = NameNodeClient::connect("http://localhost:50051").await?;
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
             let request = tonic::Request::new(WriteFileRequest {
-                filename: "/".to_string(),
-                data: vec![],
+                filename,
+                data: data.into_bytes(),
                 nodes_left: vec![],
             });
             let response = client.write_file(request).await?;
             rprintln!("Response: {:?}", response);
         },
+        "get" => {
+            if args.is_empty() {
+                rprintln!("Usage: get <filename>");
+                return Ok(());
+            }
+            let filename = args[0].to_string();
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let request = tonic::Request::new(GetBlockLocationsRequest {
+                filename,
+                offset: 0,
+                length: 0,
+            });
+            let response = client.get_block_locations(request).await?.into_inner();
+            let mut data = Vec::new();
+            for block in response.blocks {
+                data.extend_from_slice(&read_block(&block, 0, 0).await?);
+            }
+            rprintln!("{}", String::from_utf8_lossy(&data));
+        },
+        "pread" => {
+            let range = args.get(1).zip(args.get(2)).and_then(|(offset, length)| Some((offset.parse::<u64>().ok()?, length.parse::<u64>().ok()?)));
+            let Some((offset, length)) = range else {
+                rprintln!("Usage: pread <filename> <offset> <length>");
+                return Ok(());
+            };
+            let mut client = NameNodeClient::connect(NAMENODE_ADDR).await?;
+            let request = tonic::Request::new(GetBlockLocationsRequest {
+                filename: args[0].to_string(),
+                offset,
+                length,
+            });
+            let response = client.get_block_locations(request).await?.into_inner();
+            let end = if length == 0 { response.file_length } else { offset.saturating_add(length).min(response.file_length) };
+            let mut data = Vec::new();
+            for block in response.blocks {
+                let start = offset.max(block.offset) - block.offset;
+                let block_end = end.min(block.offset + block.length) - block.offset;
+                data.extend_from_slice(&read_block(&block, start, block_end - start).await?);
+            }
+            rprintln!("{}", String::from_utf8_lossy(&data));
+        },
         _ => rprintln!("Unknown command: {}", command),
     }
     Ok(())
 }
 
 
+// read_block streams length bytes of a block from offset (length 0 reading to the end of the block) straight from the data nodes
+// holding it, trying each replica in order until one answers with data that matches its checksums
+async fn read_block(block: &LocatedBlock, offset: u64, length: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut last_error = format!("No replicas of block {}", block.block_id);
+    for node in &block.nodes {
+        match fetch_range(&format!("{}:{}", node.host, node.port), &block.block_id, block.gen_stamp, offset, length).await {
+            Ok(data) => return Ok(data),
+            Err(e) => last_error = format!("Failed to read block {} from {}:{}: {}", block.block_id, node.host, node.port, e.message()),
+        }
+    }
+    Err(last_error.into())
+}
+
+
 // add_to_history adds a command to the history file, also checks if the command is already in the history, if it is, it removes the old one, and adds the new one
 fn add_to_history(history_path: &PathBuf, command: &str) -> io::Result<()> {
 
@@ -260,6 +377,6 @@ fn load_history(history_path: &PathBuf) -> Vec<String> {
     let reader = BufReader::new(file);
 
     reader.lines()
-        .filter_map(|line| line.ok())
+        .map_while(Result::ok)
         .collect::<Vec<String>>()
 }
\ No newline at end of file
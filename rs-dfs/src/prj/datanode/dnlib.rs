@@ -1,26 +1,444 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
-use tonic::{Request, Response, Status};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tonic::{Request, Response, Status, Streaming};
 use crate::datanode::data_node_client::DataNodeClient;
 use crate::datanode::data_node_server::DataNode;
-use crate::datanode::{PulseRequest, PulseResponse, GetDataRequest, GetDataResponse, PutDataRequest, PutDataResponse};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::datanode::{PulseRequest, PulseResponse, GetDataRequest, GetDataResponse, PutDataRequest, PutDataResponse, PipelineAck, DataPacket, PutDataPacket, DeleteBlocksRequest, DeleteBlocksResponse};
+use crate::namenode::name_node_client::NameNodeClient;
+use crate::namenode::{RegisterDataNodeRequest, HeartbeatRequest, ReportedBlock, BlockReportRequest, IncrementalBlockReportRequest, ReportBadBlocksRequest, DataNodeCommand, CommandAck};
+use crate::namenode::data_node_command::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::sync::{mpsc, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+use rs_dfs::BlockId;
+use rs_dfs::pipeline::pipeline_acks;
+use rs_dfs::checksum::{self, BYTES_PER_CHECKSUM, PACKET_SIZE};
+use crate::storage::{self, BlockReader, BlockWriter, Deletion, StorageError};
+
+// the file in the data directory holding its storage ID
+const STORAGE_ID_FILE: &str = "STORAGE_ID";
+// packets a streamed block may have in flight between a sender and a slower receiver
+const STREAM_BUFFER: usize = 4;
+
 pub struct DataNodeState {
     data_dir: String,
+    // the NameNode the data node registered with, corrupt blocks are reported there
+    namenode_addr: Option<String>,
+    // the ID the namenode handed back on registration, None until registered
+    datanode_id: Option<String>,
+    storage_id: String,
+    // bytes the data node offers, as reported on registration
+    capacity: u64,
 }
 
+#[derive(Clone)]
 pub struct DataNodeService {
     state: Arc<RwLock<DataNodeState>>,
+    // number of get_data and put_data calls in flight
+    active_transfers: Arc<AtomicU32>,
+    // replicas received and deleted since the last incremental block report
+    pending_report: Arc<Mutex<IncrementalReport>>,
+    // outcomes of namenode commands waiting for the next heartbeat
+    pending_acks: Arc<Mutex<Vec<CommandAck>>>,
+    // notified when the namenode sends a shutdown command
+    shutdown: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct IncrementalReport {
+    received: Vec<ReportedBlock>,
+    deleted: Vec<String>,
+}
+
+// Transfer counts a get_data or put_data call as active for as long as it lives
+struct Transfer(Arc<AtomicU32>);
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl DataNodeService {
     pub fn new(data_dir: String) -> Self {
-        DataNodeService { state: Arc::new(RwLock::new(DataNodeState { data_dir })) }
+        let state = DataNodeState { data_dir, namenode_addr: None, datanode_id: None, storage_id: String::new(), capacity: 0 };
+        DataNodeService {
+            state: Arc::new(RwLock::new(state)),
+            active_transfers: Arc::new(AtomicU32::new(0)),
+            pending_report: Arc::new(Mutex::new(IncrementalReport::default())),
+            pending_acks: Arc::new(Mutex::new(Vec::new())),
+            shutdown: Arc::new(Notify::new()),
+        }
+    }
+
+    // shutdown_requested resolves once the namenode has told the data node to shut down
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await
+    }
+
+    fn start_transfer(&self) -> Transfer {
+        self.active_transfers.fetch_add(1, Ordering::SeqCst);
+        Transfer(self.active_transfers.clone())
+    }
+
+    // register Exhaustive Explanation:
+    //    1. Load the storage ID of the data directory, creating one on first start
+    //    2. Dial the NameNode and call the RegisterDataNode method with the storage ID, the address to reach this data node at and its capacity
+    //    3. Remember the data node ID the NameNode replied with
+    pub async fn register(&self, namenode_addr: &str, host: &str, port: u32, capacity: u64) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let storage_id = load_or_create_storage_id(Path::new(&self.state.read().await.data_dir))?;
+        let mut client = NameNodeClient::connect(namenode_addr.to_string()).await?;
+        let request = Request::new(RegisterDataNodeRequest { storage_id: storage_id.clone(), host: host.to_string(), port, capacity });
+        let datanode_id = client.register_data_node(request).await?.into_inner().datanode_id;
+        let mut state = self.state.write().await;
+        state.namenode_addr = Some(namenode_addr.to_string());
+        state.datanode_id = Some(datanode_id.clone());
+        state.storage_id = storage_id;
+        state.capacity = capacity;
+        Ok(datanode_id)
+    }
+
+    // heartbeat Exhaustive Explanation:
+    //    1. Measure the bytes used by the blocks in the data directory and check that the directory is still usable
+    //    2. Dial the NameNode and call the Heartbeat method with the data node ID, storage ID, usage, number of active transfers and the acks of finished commands
+    //    3. Put the acks back to be sent again if the NameNode could not be reached or did not know the data node
+    //    4. Start every command in the reply in the background, its ack goes out with a later heartbeat
+    //    5. Return whether the NameNode asked the data node to register again, which it also has to do if it never registered
+    pub async fn heartbeat(&self, namenode_addr: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let request = {
+            let state = self.state.read().await;
+            let Some(datanode_id) = state.datanode_id.clone() else {
+                return Ok(true);
+            };
+            let data_dir = Path::new(&state.data_dir);
+            let used = used_bytes(data_dir);
+            HeartbeatRequest {
+                datanode_id,
+                storage_id: state.storage_id.clone(),
+                capacity: state.capacity,
+                used,
+                remaining: state.capacity.saturating_sub(used),
+                active_transfers: self.active_transfers.load(Ordering::SeqCst),
+                failed_volumes: if is_healthy(data_dir) { 0 } else { 1 },
+                acks: std::mem::take(&mut *self.pending_acks.lock().unwrap()),
+            }
+        };
+        let acks = request.acks.clone();
+        let sent = async {
+            let mut client = NameNodeClient::connect(namenode_addr.to_string()).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(client.heartbeat(Request::new(request)).await?.into_inner())
+        };
+        let response = match sent.await {
+            Ok(response) if !response.reregister => response,
+            result => {
+                self.pending_acks.lock().unwrap().splice(0..0, acks);
+                return result.map(|_| true);
+            }
+        };
+        let mut reregister = false;
+        for command in response.commands {
+            reregister |= matches!(command.command, Some(Command::Reregister(_)));
+            let service = self.clone();
+            tokio::spawn(async move {
+                let ack = service.execute(command).await;
+                service.pending_acks.lock().unwrap().push(ack);
+            });
+        }
+        Ok(reregister)
+    }
+
+    // execute Exhaustive Explanation:
+    //    1. Replicate: read the local replica and push it through a write pipeline of the targets
+    //    2. Delete: remove the blocks from the data directory, queueing them for the next incremental block report
+    //    3. Reregister: nothing to do here, heartbeat already told the caller to register again
+    //    4. Shutdown: wake up whoever waits on shutdown_requested
+    //    5. Return the ack carrying the outcome
+    async fn execute(&self, command: DataNodeCommand) -> CommandAck {
+        let result = match command.command {
+            Some(Command::Replicate(replicate)) => match BlockId::parse(&replicate.block_id) {
+                Ok(block_id) => self.replicate(block_id, replicate.gen_stamp, replicate.targets).await,
+                Err(e) => Err(e.into()),
+            },
+            Some(Command::Delete(delete)) => match BlockId::parse_all(&delete.block_ids) {
+                Ok(block_ids) => self.delete_local_blocks(block_ids, delete.gen_stamp).await,
+                Err(e) => Err(e.into()),
+            },
+            Some(Command::Reregister(_)) => Ok(()),
+            Some(Command::Shutdown(_)) => {
+                self.shutdown.notify_one();
+                Ok(())
+            }
+            None => Err(Status::invalid_argument("Empty command")),
+        };
+        match result {
+            Ok(()) => CommandAck { command_id: command.command_id, success: true, message: String::new() },
+            Err(e) => CommandAck { command_id: command.command_id, success: false, message: e.message().to_string() },
+        }
+    }
+
+    // replicate streams the local replica of block_id to the targets a packet at a time, the first one heads the pipeline,
+    // it only succeeds if every target acknowledged the block; a local replica older than gen_stamp is not copied
+    async fn replicate(&self, block_id: BlockId, gen_stamp: u64, targets: Vec<String>) -> Result<(), Status> {
+        let _transfer = self.start_transfer();
+        let (first_node, nodes_left) = targets.split_first().ok_or_else(|| Status::invalid_argument("No targets to replicate to"))?;
+        let data_dir = self.state.read().await.data_dir.clone();
+        let opened = BlockReader::open(Path::new(&data_dir), &block_id).and_then(|reader| reader.check_gen_stamp(gen_stamp).map(|()| reader));
+        let mut reader = match opened {
+            Ok(reader) => reader,
+            Err(e) => return Err(self.storage_status(&block_id, e).await),
+        };
+        let mut downstream = Downstream::open(first_node).await?;
+        let mut header = Some((block_id.to_string(), nodes_left.to_vec(), reader.gen_stamp()));
+        loop {
+            let (data, checksums) = match reader.next_packet(PACKET_SIZE / BYTES_PER_CHECKSUM) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => return Err(self.storage_status(&block_id, e).await),
+            };
+            let (block_id, nodes_left, gen_stamp) = header.take().unwrap_or_default();
+            downstream.send(PutDataPacket { block_id, nodes_left, data, checksums, last_packet: false, gen_stamp }).await?;
+        }
+        let (block_id, nodes_left, gen_stamp) = header.unwrap_or_default();
+        downstream.send(PutDataPacket { block_id, nodes_left, last_packet: true, gen_stamp, ..Default::default() }).await?;
+        let acks = pipeline_acks(first_node, downstream.finish().await);
+        match acks.iter().find(|ack| !ack.success) {
+            Some(failed) => Err(Status::internal(format!("Failed to replicate to {}: {}", failed.node, failed.message))),
+            None if acks.len() < targets.len() => Err(Status::internal("Pipeline did not acknowledge the block")),
+            None => Ok(()),
+        }
+    }
+
+    // read_local_range reads the chunks of a block holding a range and their checksums from the data directory, a block failing
+    // verification is reported to the NameNode as corrupt and read as data loss
+    async fn read_local_range(&self, block_id: &BlockId, gen_stamp: u64, offset: u64, length: u64) -> Result<(u64, Vec<u8>, Vec<u32>), Status> {
+        let data_dir = self.state.read().await.data_dir.clone();
+        match storage::read_range(Path::new(&data_dir), block_id, gen_stamp, offset, length) {
+            Ok(block) => Ok(block),
+            Err(e) => Err(self.storage_status(block_id, e).await),
+        }
+    }
+
+    // storage_status turns a storage error into the status handed back to the caller, reporting a corrupt block to the NameNode on the way;
+    // a block that fails verification is read once more first, it may have been caught in the middle of being replaced by a newer copy
+    async fn storage_status(&self, block_id: &BlockId, error: StorageError) -> Status {
+        match error {
+            StorageError::Io(e) => Status::internal(format!("Failed to access block {}: {}", block_id, e)),
+            e @ StorageError::Misaligned { .. } => Status::invalid_argument(e.to_string()),
+            e @ StorageError::OutOfRange { .. } => Status::out_of_range(e.to_string()),
+            e @ StorageError::Stale { .. } => Status::failed_precondition(e.to_string()),
+            e @ StorageError::Corrupt { .. } => {
+                let data_dir = self.state.read().await.data_dir.clone();
+                if storage::verify_block(Path::new(&data_dir), block_id) {
+                    return Status::unavailable(format!("Block {} changed while it was read, read it again", block_id));
+                }
+                if let Err(report_error) = self.report_bad_blocks(vec![block_id.to_string()]).await {
+                    println!("Failed to report corrupt block {}: {}", block_id, report_error);
+                }
+                Status::data_loss(e.to_string())
+            }
+        }
+    }
+
+    // receive_block Exhaustive Explanation:
+    //    1. Open a writer for the block, whose ID the caller already parsed out of the first packet, stamping it with the first packet's generation stamp
+    //    2. If the first packet names nodes left in the pipeline, open a PutDataStream to the next one
+    //    3. For each packet, compute its checksums if the sender sent none, then verify and write it, and forward it downstream with the pipeline minus the next node
+    //    4. A downstream node that fails is not this node's failure, stop forwarding and keep writing the local replica
+    //    5. Refuse the block if the stream ended before the last packet, the sender gave up on it
+    //    6. Sync the block and wait for the rest of the pipeline to acknowledge it, then rename it into the finalized directory and return its length
+    //       together with the acks of the downstream nodes; bailing out anywhere before that drops the writer, which removes the in-progress block
+    async fn receive_block(&self, data_dir: &Path, block_id: &BlockId, first: PutDataPacket, packets: &mut Streaming<PutDataPacket>) -> Result<(u64, Vec<PipelineAck>), Status> {
+        let mut writer = BlockWriter::create(data_dir, block_id, first.gen_stamp).map_err(|e| Status::internal(format!("Failed to create block {}: {}", block_id, e)))?;
+        let next_node = first.nodes_left.first().cloned();
+        let mut downstream = match &next_node {
+            Some(next_node) => Some(Downstream::open(next_node).await),
+            None => None,
+        };
+        let mut complete = false;
+        let mut next = Some(first);
+        while let Some(mut packet) = next {
+            complete = packet.last_packet;
+            if packet.checksums.is_empty() {
+                packet.checksums = checksum::chunk_checksums(&packet.data);
+            }
+            if let Err(e) = writer.write_packet(&packet.data, &packet.checksums) {
+                return Err(match e {
+                    StorageError::Corrupt { chunk, .. } => Status::data_loss(format!("Block {} arrived corrupt at chunk {}", block_id, chunk)),
+                    e => self.storage_status(block_id, e).await,
+                });
+            }
+            if let Some(Ok(stream)) = downstream.as_mut() {
+                if !packet.nodes_left.is_empty() {
+                    packet.nodes_left.remove(0);
+                }
+                if let Err(e) = stream.send(packet).await {
+                    downstream = Some(Err(e));
+                }
+            }
+            next = if complete { None } else { packets.message().await? };
+        }
+        if !complete {
+            return Err(Status::aborted(format!("Stream of block {} ended before its last packet", block_id)));
+        }
+        writer.sync().map_err(|e| Status::internal(format!("Failed to write block {}: {}", block_id, e)))?;
+        let acks = match (next_node, downstream) {
+            (Some(next_node), Some(Ok(stream))) => pipeline_acks(&next_node, stream.finish().await),
+            (Some(next_node), Some(Err(e))) => pipeline_acks(&next_node, Err(e)),
+            _ => Vec::new(),
+        };
+        let length = writer.finish().map_err(|e| Status::internal(format!("Failed to write block {}: {}", block_id, e)))?;
+        Ok((length, acks))
+    }
+
+    // delete_local_blocks removes the blocks and their meta files from the data directory, a block that is already gone counts as deleted;
+    // a replica stored with a generation stamp newer than gen_stamp is kept, it is not the one the caller meant
+    async fn delete_local_blocks(&self, block_ids: Vec<BlockId>, gen_stamp: u64) -> Result<(), Status> {
+        let state = self.state.read().await;
+        for block_id in block_ids {
+            match storage::delete_block(Path::new(&state.data_dir), &block_id, gen_stamp) {
+                Ok(Deletion::Deleted) => self.pending_report.lock().unwrap().deleted.push(block_id.into()),
+                Ok(Deletion::Missing) => {}
+                Ok(Deletion::Newer(stored)) => println!("Keeping block {}, its generation stamp {} is newer than {}", block_id, stored, gen_stamp),
+                Err(e) => return Err(Status::internal(format!("Failed to delete block {}: {}", block_id, e))),
+            }
+        }
+        Ok(())
+    }
+
+    // report_bad_blocks Exhaustive Explanation:
+    //    1. Nothing can be reported before the data node registered, the block is reported again on its next failed read
+    //    2. Dial the NameNode the data node registered with and call the ReportBadBlocks method with the data node ID, storage ID and blocks
+    //    3. Return whether the NameNode asked the data node to register again
+    pub async fn report_bad_blocks(&self, block_ids: Vec<String>) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let (namenode_addr, request) = {
+            let state = self.state.read().await;
+            let (Some(namenode_addr), Some(datanode_id)) = (state.namenode_addr.clone(), state.datanode_id.clone()) else {
+                return Ok(true);
+            };
+            (namenode_addr, ReportBadBlocksRequest { datanode_id, storage_id: state.storage_id.clone(), block_ids })
+        };
+        let mut client = NameNodeClient::connect(namenode_addr).await?;
+        Ok(client.report_bad_blocks(Request::new(request)).await?.into_inner().reregister)
+    }
+
+    // block_report Exhaustive Explanation:
+    //    1. List every block in the data directory with its length and generation stamp, leaving out blocks whose meta file cannot be read
+    //    2. Dial the NameNode and call the BlockReport method with the data node ID, storage ID and blocks
+    //    3. Log the replicas the NameNode flagged, stale ones come back as delete commands, and return whether it asked the data node to register again
+    pub async fn block_report(&self, namenode_addr: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let request = {
+            let state = self.state.read().await;
+            let Some(datanode_id) = state.datanode_id.clone() else {
+                return Ok(true);
+            };
+            let data_dir = Path::new(&state.data_dir);
+            let blocks = list_blocks(data_dir)
+                .into_iter()
+                .filter_map(|(block_id, length)| {
+                    let gen_stamp = storage::read_gen_stamp(data_dir, &block_id).ok()?;
+                    Some(ReportedBlock { block_id: block_id.into(), length, gen_stamp })
+                })
+                .collect();
+            BlockReportRequest { datanode_id, storage_id: state.storage_id.clone(), blocks }
+        };
+        let mut client = NameNodeClient::connect(namenode_addr.to_string()).await?;
+        let response = client.block_report(Request::new(request)).await?.into_inner();
+        if !response.missing_block_ids.is_empty() || !response.orphaned_block_ids.is_empty() {
+            println!("Block report: namenode expected {:?}, does not know {:?}", response.missing_block_ids, response.orphaned_block_ids);
+        }
+        if !response.stale_block_ids.is_empty() {
+            println!("Block report: replicas {:?} are stale", response.stale_block_ids);
+        }
+        Ok(response.reregister)
+    }
+
+    // incremental_block_report Exhaustive Explanation:
+    //    1. Take the replicas received and deleted since the last report, there is nothing to send if there are none
+    //    2. Dial the NameNode and call the IncrementalBlockReport method with them
+    //    3. Put them back to be sent again if the NameNode could not be reached or asked the data node to register again
+    pub async fn incremental_block_report(&self, namenode_addr: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(datanode_id) = self.state.read().await.datanode_id.clone() else {
+            return Ok(true);
+        };
+        let report = std::mem::take(&mut *self.pending_report.lock().unwrap());
+        if report.received.is_empty() && report.deleted.is_empty() {
+            return Ok(false);
+        }
+        let request = IncrementalBlockReportRequest {
+            datanode_id,
+            storage_id: self.state.read().await.storage_id.clone(),
+            received: report.received.clone(),
+            deleted_block_ids: report.deleted.clone(),
+        };
+        let sent = async {
+            let mut client = NameNodeClient::connect(namenode_addr.to_string()).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(client.incremental_block_report(Request::new(request)).await?.into_inner().reregister)
+        };
+        let result = sent.await;
+        if !matches!(result, Ok(false)) {
+            let mut pending = self.pending_report.lock().unwrap();
+            pending.received.splice(0..0, report.received);
+            pending.deleted.splice(0..0, report.deleted);
+        }
+        result
     }
 }
 
+// list_blocks returns the ID and length of every finalized block in data_dir, skipping meta files and anything else
+// whose name is not a block ID, blocks still being written are not listed
+pub fn list_blocks(data_dir: &Path) -> Vec<(BlockId, u64)> {
+    let Ok(entries) = fs::read_dir(storage::finalized_dir(data_dir)) else {
+        return Vec::new();
+    };
+    let mut blocks: Vec<(BlockId, u64)> = entries.filter_map(Result::ok)
+        .filter_map(|entry| {
+            let block_id = BlockId::parse(entry.file_name().to_str()?).ok()?;
+            let metadata = entry.metadata().ok()?;
+            metadata.is_file().then_some((block_id, metadata.len()))
+        })
+        .collect();
+    blocks.sort();
+    blocks
+}
+
+// used_bytes sums the sizes of the blocks in data_dir
+fn used_bytes(data_dir: &Path) -> u64 {
+    list_blocks(data_dir).iter().map(|(_, length)| length).sum()
+}
+
+// is_healthy checks that a block could be written to and removed from data_dir right now,
+// every probe gets a file of its own so probes running at the same time do not remove each other's
+fn is_healthy(data_dir: &Path) -> bool {
+    let probe = data_dir.join(format!(".probe-{}", Uuid::new_v4()));
+    let written = File::options().write(true).create_new(true).open(&probe);
+    let healthy = written.and_then(|mut file| file.write_all(b"probe")).is_ok();
+    let removed = fs::remove_file(probe).is_ok();
+    healthy && removed
+}
+
+// load_or_create_storage_id returns the storage ID persisted in data_dir, generating and persisting a new one if there is none yet,
+// the ID stays with the data directory so the namenode recognises its replicas even if the data node comes back at another address
+pub fn load_or_create_storage_id(data_dir: &Path) -> std::io::Result<String> {
+    let path = data_dir.join(STORAGE_ID_FILE);
+    match fs::read_to_string(&path) {
+        Ok(storage_id) if !storage_id.trim().is_empty() => return Ok(storage_id.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    fs::create_dir_all(data_dir)?;
+    let storage_id = format!("DS-{}", Uuid::new_v4());
+    let mut file = File::create(&path)?;
+    file.write_all(storage_id.as_bytes())?;
+    file.sync_all()?;
+    Ok(storage_id)
+}
+
 #[tonic::async_trait]
 impl DataNode for DataNodeService {
     // Pulse Exhaustive Explanation:
@@ -28,68 +446,204 @@ impl DataNode for DataNodeService {
     //     - the datanode should respond with a PulseResponse with success = true if it is still alive or if the namenode is sending an initial ping
     //     - the datanode should respond with a PulseResponse with success = false if there's something wrong with the datanode (disk error, network error, etc.) 
     //         or if the datanode is not able to serve requests, or if the namenode is sending an initial ping and that has failed (the datanode is already registered with another namenode)
-    async fn pulse(&self, request: Request<PulseRequest>) -> Result<Response<PulseResponse>, Status> {
-        let req = request.into_inner();
-        let success = if req.pulse {
-            // Handle heartbeat
-            true
-        } else {
-            // Handle initial ping
-            true
-        };
+    async fn pulse(&self, _request: Request<PulseRequest>) -> Result<Response<PulseResponse>, Status> {
+        // both modes answer the same way: alive as long as blocks can still be stored in the data directory
+        let state = self.state.read().await;
+        let success = is_healthy(Path::new(&state.data_dir));
         Ok(Response::new(PulseResponse { success }))
     }
     // get_data Exhaustive Explanation:
-    //      1. Get the block ID from the request
-    //      2. Read the file from the data directory with the block ID as the name
-    //      3. Return the data to the NameNode
+    //      1. Get the block ID and the range to read from the request, refusing a block ID that is not well formed
+    //      2. Refuse the read if the replica is older than the generation stamp the reader expects
+    //      3. Read the chunks of the file in the data directory with the block ID as the name holding the range together with their checksums from the meta file
+    //      4. Verify the data against the checksums, a corrupt block is reported to the NameNode and read as data loss
+    //      5. Return the data, where it starts in the block and the checksums so the reader can verify them again
     async fn get_data(&self, request: Request<GetDataRequest>) -> Result<Response<GetDataResponse>, Status> {
         let req = request.into_inner();
-        let state = self.state.read().await;
-        let file_path = Path::new(&state.data_dir).join(req.filename);  
-        let mut file = File::open(file_path).map_err(|e| Status::internal(format!("Failed to open file: {}", e)))?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).map_err(|e| Status::internal(format!("Failed to read file: {}", e)))?;
-        Ok(Response::new(GetDataResponse { data }))
+        let _transfer = self.start_transfer();
+        let block_id = BlockId::parse(&req.filename)?;
+        let (offset, data, checksums) = self.read_local_range(&block_id, req.gen_stamp, req.offset, req.length).await?;
+        Ok(Response::new(GetDataResponse { data, checksums, bytes_per_checksum: BYTES_PER_CHECKSUM as u32, offset }))
     }
 
     // put_data Exhaustive Explanation:
-    //    1. Create a new file in the data directory with the block ID as the name
-    //    2. Write the data to the file
-    //    3. Flush the file writer
-    //    4. Forward the data to the next data node for replication
+    //    1. Refuse a block ID that is not well formed, then verify the data against the checksums the sender computed, or compute them if it sent none
+    //    2. Start forwarding the data and checksums to the next data node for replication in the background
+    //    3. Meanwhile write the data to a file in the data directory with the block ID as the name and the checksums and generation stamp to its meta file
+    //    4. Queue the block for the next incremental block report
+    //    5. Wait for the rest of the pipeline and reply with its acks, a failed downstream node does not fail the local write
     async fn put_data(&self, request: Request<PutDataRequest>) -> Result<Response<PutDataResponse>, Status> {
         let req = request.into_inner();
-        let state = self.state.read().await;
-        let file_path = Path::new(&state.data_dir).join(req.block_id.clone());
-        let mut file = OpenOptions::new().create(true).write(true).open(file_path).map_err(|e| Status::internal(format!("Failed to create file: {}", e)))?;
-        file.write_all(&req.data).map_err(|e| Status::internal(format!("Failed to write file: {}", e)))?;
-        file.flush().map_err(|e| Status::internal(format!("Failed to flush file: {}", e)))?;
-        if req.nodes_left.len() > 0 {
-            pass_data_onto_next_dn(req.block_id.clone(), req.data, req.nodes_left).await?;
+        let block_id = BlockId::parse(&req.block_id)?;
+        let _transfer = self.start_transfer();
+        let checksums = if req.checksums.is_empty() {
+            checksum::chunk_checksums(&req.data)
+        } else {
+            checksum::verify(&req.data, &req.checksums)
+                .map_err(|chunk| Status::data_loss(format!("Block {} arrived corrupt at chunk {}", req.block_id, chunk)))?;
+            req.checksums
+        };
+        let forward = req.nodes_left.first().cloned().map(|next_node| {
+            (next_node, tokio::spawn(pass_data_onto_next_dn(req.block_id.clone(), req.gen_stamp, req.data.clone(), checksums.clone(), req.nodes_left.clone())))
+        });
+        let data_dir = self.state.read().await.data_dir.clone();
+        if let Err(e) = storage::write_block(Path::new(&data_dir), &block_id, req.gen_stamp, &req.data, &checksums) {
+            if let Some((_, forward)) = forward {
+                forward.abort();
+            }
+            return Err(self.storage_status(&block_id, e).await);
         }
-        Ok(Response::new(PutDataResponse { success: true }))
+        self.pending_report.lock().unwrap().received.push(ReportedBlock { block_id: req.block_id.clone(), length: req.data.len() as u64, gen_stamp: req.gen_stamp });
+        let acks = match forward {
+            Some((next_node, forward)) => forward.await.unwrap_or_else(|e| pipeline_acks(&next_node, Err(Status::internal(e.to_string())))),
+            None => Vec::new(),
+        };
+        Ok(Response::new(PutDataResponse { success: true, acks }))
     }
 
+    type GetDataStreamStream = ReceiverStream<Result<DataPacket, Status>>;
+
+    // get_data_stream Exhaustive Explanation:
+    //    1. Refuse a block ID that is not well formed, then open the block and its meta file from the data directory, refuse a replica older than the
+    //       generation stamp the reader expects and seek to the chunks holding the requested range
+    //    2. In the background, read the range a packet at a time, verify each packet against its checksums and send it over a bounded channel,
+    //       so no more than a few packets are held in memory while the reader catches up
+    //    3. A packet failing verification ends the stream with data loss and gets the block reported to the NameNode
+    async fn get_data_stream(&self, request: Request<GetDataRequest>) -> Result<Response<Self::GetDataStreamStream>, Status> {
+        let req = request.into_inner();
+        let block_id = BlockId::parse(&req.filename)?;
+        let transfer = self.start_transfer();
+        let data_dir = self.state.read().await.data_dir.clone();
+        let opened = BlockReader::open(Path::new(&data_dir), &block_id)
+            .and_then(|mut reader| {
+                reader.check_gen_stamp(req.gen_stamp)?;
+                Ok((reader.seek_range(req.offset, req.length)?, reader))
+            });
+        let (mut offset, mut reader) = match opened {
+            Ok(opened) => opened,
+            Err(e) => return Err(self.storage_status(&block_id, e).await),
+        };
+        let (packets, stream) = mpsc::channel(STREAM_BUFFER);
+        let service = self.clone();
+        tokio::spawn(async move {
+            let _transfer = transfer;
+            loop {
+                let packet = match reader.next_packet(PACKET_SIZE / BYTES_PER_CHECKSUM) {
+                    Ok(Some((data, checksums))) => {
+                        let packet = DataPacket { data, checksums, bytes_per_checksum: BYTES_PER_CHECKSUM as u32, offset };
+                        offset += packet.data.len() as u64;
+                        Ok(packet)
+                    }
+                    Ok(None) => break,
+                    Err(e) => Err(service.storage_status(&block_id, e).await),
+                };
+                let failed = packet.is_err();
+                // the reader went away
+                if packets.send(packet).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    // put_data_stream Exhaustive Explanation:
+    //    1. Read the block ID and the pipeline from the first packet, refusing a block ID that is not well formed
+    //    2. Receive the block packet by packet, writing each one and forwarding it to the next data node in the pipeline
+    //    3. Queue the block for the next incremental block report once the rest of the pipeline answered, and reply with its acks
+    //    4. A stream that failed or was cancelled part way leaves nothing behind, the block only becomes visible once it is complete
+    async fn put_data_stream(&self, request: Request<Streaming<PutDataPacket>>) -> Result<Response<PutDataResponse>, Status> {
+        let mut packets = request.into_inner();
+        let _transfer = self.start_transfer();
+        let first = packets.message().await?.ok_or_else(|| Status::invalid_argument("Empty block stream"))?;
+        let data_dir = PathBuf::from(&self.state.read().await.data_dir);
+        let block_id = BlockId::parse(&first.block_id)?;
+        let gen_stamp = first.gen_stamp;
+        let (length, acks) = self.receive_block(&data_dir, &block_id, first, &mut packets).await?;
+        self.pending_report.lock().unwrap().received.push(ReportedBlock { block_id: block_id.into(), length, gen_stamp });
+        Ok(Response::new(PutDataResponse { success: true, acks }))
+    }
+
+    // delete_blocks Exhaustive Explanation:
+    //    1. Refuse the request if any block ID is not well formed, then remove the file with each block ID as the name from the data directory
+    //    2. A block that is already gone counts as deleted
+    //    3. Queue the removed blocks for the next incremental block report
+    //    4. Return success once every block is gone
+    async fn delete_blocks(&self, request: Request<DeleteBlocksRequest>) -> Result<Response<DeleteBlocksResponse>, Status> {
+        let req = request.into_inner();
+        self.delete_local_blocks(BlockId::parse_all(&req.block_ids)?, u64::MAX).await?;
+        Ok(Response::new(DeleteBlocksResponse { success: true }))
+    }
 }
 
 
 // pass_data_onto_next_dn Exhaustive Explanation:
-//      1. Get the block ID and the replication nodes from the request
-//      2. If there are no replication nodes, return nil
+//      1. Get the block ID, its generation stamp and the replication nodes from the request
+//      2. If there are no replication nodes, return no acks
 //      3. Get the starting data node from the block addresses
 //      4. Get the remaining data nodes from the block addresses
 //      5. Dial the starting data node and call the PutData method
 //      6. Forward the data to the next data node for replication by calling the PutData method on the next data node
-async fn pass_data_onto_next_dn(block_id: String, data: Vec<u8>, nodes_left: Vec<String>) -> Result<Response<PutDataResponse>, Status> {
+//      7. Return the acks of the starting data node and the ones after it
+async fn pass_data_onto_next_dn(block_id: String, gen_stamp: u64, data: Vec<u8>, checksums: Vec<u32>, nodes_left: Vec<String>) -> Vec<PipelineAck> {
+    let Some(first_node) = nodes_left.first() else {
+        return Vec::new();
+    };
     let put_data_request = PutDataRequest {
         block_id,
         data,
         nodes_left: nodes_left[1..].to_vec(),
+        checksums,
+        gen_stamp,
     };
     // make grpc call to the next node, the nodes_left is formatted like this: "host:port,host:port,host:port"
-    let first_node = nodes_left.first().ok_or_else(|| Status::internal("No nodes left"))?;
-    let mut client = DataNodeClient::connect(format!("http://{}", first_node)).await.map_err(|e| Status::internal(format!("Failed to connect: {}", e)))?;
-    let put_data_response = client.put_data(put_data_request).await.map_err(|e| Status::internal(format!("Failed to put data: {}", e)))?;
-    Ok(put_data_response) 
-}
\ No newline at end of file
+    let outcome = async {
+        let mut client = DataNodeClient::connect(format!("http://{}", first_node)).await.map_err(|e| Status::internal(format!("Failed to connect: {}", e)))?;
+        Ok(client.put_data(put_data_request).await?.into_inner())
+    };
+    pipeline_acks(first_node, outcome.await)
+}
+
+// Downstream is a PutDataStream call to the next data node of a pipeline, fed through a bounded channel so a slow node
+// holds back the ones before it; dropping it before the last packet was sent ends the stream without one,
+// so the downstream nodes throw away what they got instead of keeping a truncated block
+struct Downstream {
+    packets: Option<mpsc::Sender<PutDataPacket>>,
+    call: JoinHandle<Result<Response<PutDataResponse>, Status>>,
+}
+
+impl Downstream {
+    async fn open(node: &str) -> Result<Self, Status> {
+        let mut client = DataNodeClient::connect(format!("http://{}", node)).await.map_err(|e| Status::internal(format!("Failed to connect: {}", e)))?;
+        let (packets, stream) = mpsc::channel(STREAM_BUFFER);
+        let call = tokio::spawn(async move { client.put_data_stream(ReceiverStream::new(stream)).await });
+        Ok(Downstream { packets: Some(packets), call })
+    }
+
+    // send forwards a packet, failing with the downstream node's error if it gave up on the stream
+    async fn send(&mut self, packet: PutDataPacket) -> Result<(), Status> {
+        let packets = self.packets.as_ref().ok_or_else(|| Status::internal("Stream already finished"))?;
+        if packets.send(packet).await.is_ok() {
+            return Ok(());
+        }
+        match (&mut self.call).await {
+            Ok(Err(e)) => Err(Status::internal(format!("Failed to put data: {}", e.message()))),
+            _ => Err(Status::internal("Downstream data node closed the stream")),
+        }
+    }
+
+    // finish ends the stream and waits for the rest of the pipeline to answer
+    async fn finish(mut self) -> Result<PutDataResponse, Status> {
+        self.packets.take();
+        let response = (&mut self.call).await
+            .map_err(|e| Status::internal(format!("Failed to put data: {}", e)))?
+            .map_err(|e| Status::internal(format!("Failed to put data: {}", e.message())))?;
+        Ok(response.into_inner())
+    }
+}
+
+impl Drop for Downstream {
+    fn drop(&mut self) {
+        self.call.abort();
+    }
+}
@@ -1,13 +1,16 @@
 use clap::{Arg, Command};
 use tonic::transport::Server;
 use crate::datanode::data_node_server::DataNodeServer;
-use crate::datanode::data_node_client::DataNodeClient;
 mod dnlib;
+mod scanner;
+mod storage;
 use dnlib::DataNodeService;
+use scanner::BlockScanner;
 use std::net::SocketAddr;
 use std::str::FromStr;
-mod datanode {
-    tonic::include_proto!("datanode");
+use rs_dfs::datanode;
+pub mod namenode {
+    tonic::include_proto!("namenode");
 }
 
 #[tokio::main]
@@ -31,10 +34,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .value_name("DATADIR")
                 .help("Sets the datadir")
         )
+        .arg(
+            Arg::new("namenode")
+                .short('n')
+                .long("namenode")
+                .value_name("NAMENODE")
+                .help("Address of the namenode to register with, e.g. localhost:8080")
+        )
+        .arg(
+            Arg::new("host")
+                .long("host")
+                .value_name("HOST")
+                .help("Sets the host the namenode and other data nodes reach this data node at")
+        )
+        .arg(
+            Arg::new("capacity")
+                .long("capacity")
+                .value_name("BYTES")
+                .help("Sets the storage capacity reported to the namenode")
+        )
+        .arg(
+            Arg::new("blockReportInterval")
+                .long("block-report-interval")
+                .value_name("SECONDS")
+                .help("Sets how often a full block report is sent to the namenode")
+        )
+        .arg(
+            Arg::new("heartbeatInterval")
+                .long("heartbeat-interval")
+                .value_name("SECONDS")
+                .help("Sets how often a heartbeat is sent to the namenode")
+        )
+        .arg(
+            Arg::new("scanBandwidth")
+                .long("scan-bandwidth")
+                .value_name("BYTES_PER_SECOND")
+                .help("Sets how many bytes of blocks the background scanner verifies per second, 0 turns it off")
+        )
+        .arg(
+            Arg::new("scanPeriod")
+                .long("scan-period")
+                .value_name("SECONDS")
+                .help("Sets how often every block is re-verified by the background scanner")
+        )
         .get_matches();
 
     let port = matches.get_one::<String>("port").map(String::as_str).unwrap_or("4210");
     let datadir = matches.get_one::<String>("datadir").map(String::as_str).unwrap_or("data");
+    let namenode = matches.get_one::<String>("namenode").cloned();
+    let host = matches.get_one::<String>("host").map(String::as_str).unwrap_or("localhost").to_string();
+    let capacity: u64 = matches.get_one::<String>("capacity").map(String::as_str).unwrap_or("10737418240").parse().unwrap();
+    let heartbeat_interval: u64 = matches.get_one::<String>("heartbeatInterval").map(String::as_str).unwrap_or("3").parse().unwrap();
+    let block_report_interval = tokio::time::Duration::from_secs(matches.get_one::<String>("blockReportInterval").map(String::as_str).unwrap_or("60").parse().unwrap());
+    let scan_bandwidth: u64 = matches.get_one::<String>("scanBandwidth").map(String::as_str).unwrap_or("1048576").parse().unwrap();
+    let scan_period: u64 = matches.get_one::<String>("scanPeriod").map(String::as_str).unwrap_or("86400").parse().unwrap();
+
+    // finish or throw away the blocks a crash left half written before anything is served or reported
+    let recovery = storage::recover(std::path::Path::new(datadir))?;
+    println!("Data directory {}: {} blocks migrated, {} recovered, {} discarded", datadir, recovery.migrated, recovery.recovered, recovery.discarded);
 
     let addr = format!("0.0.0.0:{}", port);
     let datanode: DataNodeService = DataNodeService::new(datadir.to_string());
@@ -43,9 +100,69 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("DataNode server starting on {}", addr);
     println!("Waiting for incoming requests...");
 
+    // register and then heartbeat in the background, so reads and writes can be served while the namenode is unreachable,
+    // registration is retried every interval until it succeeds and redone whenever the namenode asks for it,
+    // a full block report follows every registration and block_report_interval, incremental ones ride along with the heartbeats
+    if let Some(namenode) = namenode {
+        let reporter = datanode.clone();
+        let port: u32 = port.parse().unwrap();
+        tokio::spawn(async move {
+            let namenode_addr = format!("http://{}", namenode);
+            let mut registered = false;
+            let mut last_block_report: Option<tokio::time::Instant> = None;
+            loop {
+                if !registered {
+                    match reporter.register(&namenode_addr, &host, port, capacity).await {
+                        Ok(datanode_id) => {
+                            println!("Registered with namenode {} as {}", namenode, datanode_id);
+                            registered = true;
+                            last_block_report = None;
+                        }
+                        Err(e) => println!("Failed to register with namenode {}: {}, retrying", namenode, e),
+                    }
+                } else {
+                    match reporter.heartbeat(&namenode_addr).await {
+                        Ok(true) => {
+                            println!("Namenode {} asked to register again", namenode);
+                            registered = false;
+                            continue;
+                        }
+                        Ok(false) => {}
+                        Err(e) => println!("Failed to send heartbeat to namenode {}: {}", namenode, e),
+                    }
+                }
+                if registered && last_block_report.is_none_or(|sent| sent.elapsed() >= block_report_interval) {
+                    match reporter.block_report(&namenode_addr).await {
+                        Ok(reregister) => {
+                            registered = !reregister;
+                            last_block_report = Some(tokio::time::Instant::now());
+                        }
+                        Err(e) => println!("Failed to send block report to namenode {}: {}", namenode, e),
+                    }
+                } else if registered {
+                    match reporter.incremental_block_report(&namenode_addr).await {
+                        Ok(reregister) => registered = !reregister,
+                        Err(e) => println!("Failed to send incremental block report to namenode {}: {}", namenode, e),
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_secs(heartbeat_interval)).await;
+            }
+        });
+    }
+
+    // re-verify every block once per scan period in the background, so blocks nobody reads are still caught rotting
+    if scan_bandwidth > 0 {
+        let scanner = BlockScanner::new(datadir.into(), scan_bandwidth, scan_period * 1000);
+        tokio::spawn(scanner.run(datanode.clone()));
+    }
+
+    let shutdown = datanode.clone();
     let server = Server::builder()
         .add_service(DataNodeServer::new(datanode))
-        .serve(addr);
+        .serve_with_shutdown(addr, async move {
+            shutdown.shutdown_requested().await;
+            println!("Namenode asked the data node to shut down");
+        });
 
     // Removed the periodic printing as it's not needed for a daemon
 
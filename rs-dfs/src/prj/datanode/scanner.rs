@@ -0,0 +1,152 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::dnlib::{self, DataNodeService};
+use rs_dfs::checksum::{BYTES_PER_CHECKSUM, PACKET_SIZE};
+use crate::storage::{self, BlockReader, StorageError};
+use rs_dfs::BlockId;
+
+// the file in the data directory holding the scanner's progress, hidden so it is never mistaken for a block
+const SCAN_CURSOR_FILE: &str = ".scanner_cursor";
+
+// ScanCursor is how far the scanner got, persisted after every batch so a restart picks up where it left off
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ScanCursor {
+    // the last block verified in the current pass, None between passes
+    last_block_id: Option<String>,
+    // when the last full pass over the data directory finished, in milliseconds since the unix epoch, None before the first one
+    last_pass_finished: Option<u64>,
+}
+
+// BlockScanner re-verifies every block in the data directory against its checksums once per scan period,
+// reading at most bytes_per_second so it does not compete with clients for the disk
+pub struct BlockScanner {
+    data_dir: PathBuf,
+    bytes_per_second: u64,
+    scan_period_ms: u64,
+    cursor: ScanCursor,
+    // corrupt blocks the namenode has not been told about yet
+    unreported: Vec<String>,
+}
+
+impl BlockScanner {
+    pub fn new(data_dir: PathBuf, bytes_per_second: u64, scan_period_ms: u64) -> Self {
+        let cursor = load_cursor(&data_dir);
+        BlockScanner { data_dir, bytes_per_second, scan_period_ms, cursor, unreported: Vec::new() }
+    }
+
+    // pass_due tells whether a pass is under way or the scan period has gone by since the last one finished
+    pub fn pass_due(&self, now: u64) -> bool {
+        self.cursor.last_block_id.is_some()
+            || self.cursor.last_pass_finished.is_none_or(|finished| now >= finished.saturating_add(self.scan_period_ms))
+    }
+
+    // scan_batch verifies the blocks after the cursor in block ID order until max_bytes have been read and returns the corrupt ones,
+    // at least one block is verified per batch so a block larger than max_bytes does not stall the pass;
+    // blocks without a meta file are skipped, they get one on their first read
+    pub fn scan_batch(&mut self, max_bytes: u64, now: u64) -> io::Result<Vec<String>> {
+        let mut scanned = 0;
+        let mut finished = true;
+        let mut corrupt = Vec::new();
+        let blocks = dnlib::list_blocks(&self.data_dir);
+        let resume_after = self.cursor.last_block_id.take();
+        let pending = blocks.iter().filter(|(block_id, _)| resume_after.as_ref().is_none_or(|last| block_id.as_str() > last.as_str()));
+        for (block_id, length) in pending {
+            if scanned > 0 && scanned + length > max_bytes {
+                finished = false;
+                break;
+            }
+            if storage::meta_path(&self.data_dir, block_id).exists() {
+                match verify_block(&self.data_dir, block_id) {
+                    Ok(()) => {}
+                    Err(StorageError::Corrupt { .. } | StorageError::Misaligned { .. }) => corrupt.push(block_id.to_string()),
+                    // deleted since it was listed
+                    Err(StorageError::Io(e)) if e.kind() == ErrorKind::NotFound => {}
+                    Err(StorageError::Io(e)) => return Err(e),
+                    Err(e @ (StorageError::OutOfRange { .. } | StorageError::Stale { .. })) => return Err(io::Error::other(e.to_string())),
+                }
+            }
+            scanned += length;
+            self.cursor.last_block_id = Some(block_id.to_string());
+        }
+        if finished {
+            self.cursor = ScanCursor { last_block_id: None, last_pass_finished: Some(now) };
+        }
+        save_cursor(&self.data_dir, &self.cursor)?;
+        Ok(corrupt)
+    }
+
+    // run Exhaustive Explanation:
+    //    1. Sleep until a pass is due
+    //    2. Verify one batch of at most bytes_per_second bytes every second, moving the cursor on; the batch reads the disk,
+    //       so the scanner is handed to the blocking pool for it and taken back afterwards
+    //    3. Report the corrupt blocks to the NameNode through the data node, keeping them for the next batch if it could not be told
+    pub async fn run(mut self, datanode: DataNodeService) {
+        loop {
+            let now = now_millis();
+            if !self.pass_due(now) {
+                let next_pass = self.cursor.last_pass_finished.unwrap_or(now).saturating_add(self.scan_period_ms);
+                tokio::time::sleep(Duration::from_millis(next_pass.saturating_sub(now))).await;
+                continue;
+            }
+            let batch = tokio::task::spawn_blocking(move || {
+                let corrupt = self.scan_batch(self.bytes_per_second, now);
+                (self, corrupt)
+            });
+            let (scanner, corrupt) = match batch.await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    println!("Block scanner stopped: {}", e);
+                    return;
+                }
+            };
+            self = scanner;
+            match corrupt {
+                Ok(corrupt) => self.unreported.extend(corrupt),
+                Err(e) => println!("Block scanner failed: {}", e),
+            }
+            if !self.unreported.is_empty() {
+                match datanode.report_bad_blocks(self.unreported.clone()).await {
+                    Ok(false) => {
+                        println!("Block scanner reported corrupt blocks {:?}", self.unreported);
+                        self.unreported.clear();
+                    }
+                    Ok(true) => {}
+                    Err(e) => println!("Failed to report corrupt blocks {:?}: {}", self.unreported, e),
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+// verify_block reads the block a packet at a time, checking each packet against its checksums
+fn verify_block(data_dir: &Path, block_id: &BlockId) -> Result<(), StorageError> {
+    let mut reader = BlockReader::open(data_dir, block_id)?;
+    while reader.next_packet(PACKET_SIZE / BYTES_PER_CHECKSUM)?.is_some() {}
+    Ok(())
+}
+
+// load_cursor reads the persisted cursor, starting from scratch if there is none or it cannot be read
+fn load_cursor(data_dir: &Path) -> ScanCursor {
+    fs::read_to_string(data_dir.join(SCAN_CURSOR_FILE))
+        .ok()
+        .and_then(|cursor| serde_json::from_str(&cursor).ok())
+        .unwrap_or_default()
+}
+
+// save_cursor writes the cursor next to the persisted one and renames it over it once it is on disk,
+// so a crash part way through leaves the previous cursor rather than a torn one
+fn save_cursor(data_dir: &Path, cursor: &ScanCursor) -> io::Result<()> {
+    let temp = data_dir.join(format!("{}.tmp", SCAN_CURSOR_FILE));
+    let mut file = File::create(&temp)?;
+    file.write_all(serde_json::to_string(cursor)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(temp, data_dir.join(SCAN_CURSOR_FILE))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
@@ -0,0 +1,553 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use rs_dfs::BlockId;
+use rs_dfs::checksum::{self, BYTES_PER_CHECKSUM, PACKET_SIZE};
+use uuid::Uuid;
+
+// a finalized block lives in data_dir/current/<block_id> with its checksums next to it in data_dir/current/<block_id>.meta,
+// a block is written to data_dir/rbw (replicas being written) first and only renamed into current once it is complete and synced,
+// under a name of its writer's own, <block_id>.<nonce>, so writers of the same block never touch each other's files
+pub const META_EXTENSION: &str = "meta";
+// a writer that synced its block leaves the block's length (u64 BE) in <block_id>.<nonce>.synced, only such a block is whole
+const SYNCED_EXTENSION: &str = "synced";
+const FINALIZED_DIR: &str = "current";
+const IN_PROGRESS_DIR: &str = "rbw";
+// meta file layout: version (u16 BE), bytes per checksum (u32 BE), generation stamp (u64 BE), then one CRC32C (u32 BE) per chunk of the block;
+// version 1 meta files have no generation stamp, their blocks read as generation stamp 0
+const META_VERSION: u16 = 2;
+const META_HEADER_LEN: usize = 14;
+const META_V1_HEADER_LEN: usize = 6;
+// finalize, delete_block and write_legacy_meta lock the block they work on, so none of them lands between the renames of another;
+// blocks share a lock when their IDs hash alike, which only costs some waiting
+static BLOCK_LOCKS: [Mutex<()>; 64] = [const { Mutex::new(()) }; 64];
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    // the block does not match its checksums, chunk is the index of the first bad chunk
+    Corrupt { block_id: String, chunk: usize },
+    // a packet followed one that ended in the middle of a chunk, so its checksums cannot line up with the block's chunks
+    Misaligned { block_id: String },
+    // a read asked for bytes starting past the end of the block
+    OutOfRange { block_id: String, offset: u64, length: u64 },
+    // the replica is an older version of the block than the one asked for
+    Stale { block_id: String, gen_stamp: u64, expected: u64 },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "{}", e),
+            StorageError::Corrupt { block_id, chunk } => write!(f, "block {} is corrupt at chunk {}", block_id, chunk),
+            StorageError::Misaligned { block_id } => write!(f, "block {} got a packet after one that ended mid-chunk", block_id),
+            StorageError::OutOfRange { block_id, offset, length } => write!(f, "offset {} is past the end of block {} ({} bytes)", offset, block_id, length),
+            StorageError::Stale { block_id, gen_stamp, expected } => write!(f, "replica of block {} has generation stamp {}, older than {}", block_id, gen_stamp, expected),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+// finalized_dir is where complete blocks are kept and served from
+pub fn finalized_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(FINALIZED_DIR)
+}
+
+fn in_progress_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(IN_PROGRESS_DIR)
+}
+
+// block IDs are validated on the way in, so they can never name anything outside data_dir
+pub fn block_path(data_dir: &Path, block_id: &BlockId) -> PathBuf {
+    finalized_dir(data_dir).join(block_id.as_str())
+}
+
+pub fn meta_path(data_dir: &Path, block_id: &BlockId) -> PathBuf {
+    finalized_dir(data_dir).join(meta_name(block_id))
+}
+
+fn meta_name(block_id: &BlockId) -> String {
+    format!("{}.{}", block_id, META_EXTENSION)
+}
+
+// in_progress_name is the name a new writer of the block gives its files in the in-progress directory
+fn in_progress_name(block_id: &BlockId) -> String {
+    format!("{}.{}", block_id, Uuid::new_v4().simple())
+}
+
+fn in_progress_meta_name(name: &str) -> String {
+    format!("{}.{}", name, META_EXTENSION)
+}
+
+fn synced_name(name: &str) -> String {
+    format!("{}.{}", name, SYNCED_EXTENSION)
+}
+
+// lock_block takes the lock of the block, a panic while holding it left no torn state behind that the lock guards
+fn lock_block(block_id: &BlockId) -> MutexGuard<'static, ()> {
+    let mut hasher = DefaultHasher::new();
+    block_id.as_str().hash(&mut hasher);
+    BLOCK_LOCKS[hasher.finish() as usize % BLOCK_LOCKS.len()].lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// sync_dir makes the renames into and out of dir durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// BlockWriter stores a block packet by packet, verifying each packet against its checksums before writing it and
+// appending the checksums to the meta file, so a block of any size is written without holding it in memory;
+// the block is written to the in-progress directory and only shows up in the finalized one once finish renames it there,
+// a writer dropped before that removes what it wrote and leaves any earlier copy of the block alone
+pub struct BlockWriter {
+    data_dir: PathBuf,
+    block_id: BlockId,
+    // name of the block in the in-progress directory, its meta file adding the meta extension
+    name: String,
+    data: BufWriter<File>,
+    meta: BufWriter<File>,
+    length: u64,
+    finished: bool,
+}
+
+impl BlockWriter {
+    pub fn create(data_dir: &Path, block_id: &BlockId, gen_stamp: u64) -> io::Result<Self> {
+        let in_progress = in_progress_dir(data_dir);
+        fs::create_dir_all(&in_progress)?;
+        let name = in_progress_name(block_id);
+        let create = |name: &str| File::options().write(true).create_new(true).open(in_progress.join(name));
+        let data = BufWriter::new(create(&name)?);
+        let meta = match create(&in_progress_meta_name(&name)) {
+            Ok(meta) => BufWriter::new(meta),
+            Err(e) => {
+                let _ = fs::remove_file(in_progress.join(&name));
+                return Err(e);
+            }
+        };
+        let mut writer = BlockWriter { data_dir: data_dir.to_path_buf(), block_id: block_id.clone(), name, data, meta, length: 0, finished: false };
+        write_meta_header(&mut writer.meta, gen_stamp)?;
+        Ok(writer)
+    }
+
+    // write_packet appends a packet, every packet but the last one carrying data has to be a whole number of chunks
+    pub fn write_packet(&mut self, data: &[u8], checksums: &[u32]) -> Result<(), StorageError> {
+        if data.is_empty() && checksums.is_empty() {
+            return Ok(());
+        }
+        if !(self.length as usize).is_multiple_of(BYTES_PER_CHECKSUM) {
+            return Err(StorageError::Misaligned { block_id: self.block_id.to_string() });
+        }
+        let first_chunk = self.length as usize / BYTES_PER_CHECKSUM;
+        checksum::verify(data, checksums).map_err(|chunk| StorageError::Corrupt { block_id: self.block_id.to_string(), chunk: first_chunk + chunk })?;
+        self.data.write_all(data)?;
+        for checksum in checksums {
+            self.meta.write_all(&checksum.to_be_bytes())?;
+        }
+        self.length += data.len() as u64;
+        Ok(())
+    }
+
+    // sync flushes the block and its meta file and waits for both to reach the disk, then records the length synced so far
+    // so recover can tell the block was written whole
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.data.flush()?;
+        self.data.get_ref().sync_all()?;
+        self.meta.flush()?;
+        self.meta.get_ref().sync_all()?;
+        let in_progress = in_progress_dir(&self.data_dir);
+        let mut synced = File::create(in_progress.join(synced_name(&self.name)))?;
+        synced.write_all(&self.length.to_be_bytes())?;
+        synced.sync_all()?;
+        sync_dir(&in_progress)
+    }
+
+    // finish syncs the block and renames it into the finalized directory, meta file first so a finalized block always has
+    // its checksums, and returns the block's length; a reader opening an earlier copy of the block between the two renames
+    // pairs the new meta file with the old block and has to read it again, see verify_block
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.sync()?;
+        finalize(&self.data_dir, &self.block_id, &self.name)?;
+        self.finished = true;
+        Ok(self.length)
+    }
+}
+
+impl Drop for BlockWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let in_progress = in_progress_dir(&self.data_dir);
+            let _ = fs::remove_file(in_progress.join(&self.name));
+            let _ = fs::remove_file(in_progress.join(in_progress_meta_name(&self.name)));
+            let _ = fs::remove_file(in_progress.join(synced_name(&self.name)));
+        }
+    }
+}
+
+// finalize moves a synced block and its meta file, named name in the in-progress directory, into the finalized one,
+// replacing any earlier copy
+fn finalize(data_dir: &Path, block_id: &BlockId, name: &str) -> io::Result<()> {
+    let _lock = lock_block(block_id);
+    let (in_progress, finalized) = (in_progress_dir(data_dir), finalized_dir(data_dir));
+    fs::create_dir_all(&finalized)?;
+    fs::rename(in_progress.join(in_progress_meta_name(name)), meta_path(data_dir, block_id))?;
+    fs::rename(in_progress.join(name), block_path(data_dir, block_id))?;
+    fs::remove_file(in_progress.join(synced_name(name)))?;
+    sync_dir(&finalized)?;
+    sync_dir(&in_progress)
+}
+
+// a piece of a block together with the checksums of its chunks
+pub type Packet = (Vec<u8>, Vec<u32>);
+
+// BlockReader reads a block packet by packet, verifying each packet against the matching checksums from the meta file,
+// a block stored before checksums existed gets its meta file written when it is opened
+pub struct BlockReader {
+    block_id: BlockId,
+    data: BufReader<File>,
+    meta: BufReader<File>,
+    // length of the meta file's header, where the checksums start
+    header_len: usize,
+    gen_stamp: u64,
+    // index of the next chunk to read
+    chunk: usize,
+    length: u64,
+    // bytes left to read, up to the end of the block unless seek_range narrowed it
+    remaining: u64,
+}
+
+impl BlockReader {
+    pub fn open(data_dir: &Path, block_id: &BlockId) -> Result<Self, StorageError> {
+        let data = File::open(block_path(data_dir, block_id))?;
+        let meta = match File::open(meta_path(data_dir, block_id)) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                write_legacy_meta(data_dir, block_id)?;
+                File::open(meta_path(data_dir, block_id))?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        BlockReader::from_files(block_id, data, meta)
+    }
+
+    // from_files reads a block from an open block file and meta file, checking the meta file's header
+    fn from_files(block_id: &BlockId, data: File, meta: File) -> Result<Self, StorageError> {
+        let mut meta = BufReader::new(meta);
+        let mut header = [0u8; META_HEADER_LEN];
+        let corrupt = || StorageError::Corrupt { block_id: block_id.to_string(), chunk: 0 };
+        meta.read_exact(&mut header[..META_V1_HEADER_LEN]).map_err(|_| corrupt())?;
+        let (header_len, gen_stamp) = match u16::from_be_bytes([header[0], header[1]]) {
+            1 => (META_V1_HEADER_LEN, 0),
+            META_VERSION => {
+                meta.read_exact(&mut header[META_V1_HEADER_LEN..]).map_err(|_| corrupt())?;
+                (META_HEADER_LEN, u64::from_be_bytes(header[META_V1_HEADER_LEN..].try_into().unwrap()))
+            }
+            _ => return Err(corrupt()),
+        };
+        if u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize != BYTES_PER_CHECKSUM {
+            return Err(corrupt());
+        }
+        let length = data.metadata()?.len();
+        Ok(BlockReader { block_id: block_id.clone(), data: BufReader::new(data), meta, header_len, gen_stamp, chunk: 0, length, remaining: length })
+    }
+
+    pub fn gen_stamp(&self) -> u64 {
+        self.gen_stamp
+    }
+
+    // check_gen_stamp refuses a replica older than the version of the block the caller expects, 0 accepting any
+    pub fn check_gen_stamp(&self, expected: u64) -> Result<(), StorageError> {
+        if self.gen_stamp < expected {
+            return Err(StorageError::Stale { block_id: self.block_id.to_string(), gen_stamp: self.gen_stamp, expected });
+        }
+        Ok(())
+    }
+
+    // seek_range narrows the reader to the chunks holding length bytes from offset, length 0 reading to the end of the block,
+    // and returns the offset in the block of the first byte it will read, the start of the chunk holding offset
+    pub fn seek_range(&mut self, offset: u64, length: u64) -> Result<u64, StorageError> {
+        if offset > self.length {
+            return Err(StorageError::OutOfRange { block_id: self.block_id.to_string(), offset, length: self.length });
+        }
+        let chunk_size = BYTES_PER_CHECKSUM as u64;
+        let end = if length == 0 { self.length } else { offset.saturating_add(length).min(self.length) };
+        let start = offset / chunk_size * chunk_size;
+        let end = end.div_ceil(chunk_size).saturating_mul(chunk_size).min(self.length);
+        self.data.seek(SeekFrom::Start(start))?;
+        self.meta.seek(SeekFrom::Start(self.header_len as u64 + 4 * (start / chunk_size)))?;
+        self.chunk = (start / chunk_size) as usize;
+        self.remaining = end - start;
+        Ok(start)
+    }
+
+    // next_packet returns up to max_chunks chunks of the block with their checksums, None once the block (or range) is read,
+    // a meta file holding more or fewer checksums than the block has chunks makes the block corrupt
+    pub fn next_packet(&mut self, max_chunks: usize) -> Result<Option<Packet>, StorageError> {
+        let size = ((max_chunks * BYTES_PER_CHECKSUM) as u64).min(self.remaining);
+        let mut data = Vec::with_capacity(size as usize);
+        (&mut self.data).take(size).read_to_end(&mut data)?;
+        self.remaining -= data.len() as u64;
+        if data.is_empty() {
+            let at_end = self.chunk as u64 * BYTES_PER_CHECKSUM as u64 >= self.length;
+            if at_end && self.meta.read(&mut [0u8; 4])? > 0 {
+                return Err(StorageError::Corrupt { block_id: self.block_id.to_string(), chunk: self.chunk });
+            }
+            return Ok(None);
+        }
+        let chunks = data.len().div_ceil(BYTES_PER_CHECKSUM);
+        let mut checksums = Vec::with_capacity(chunks);
+        for index in 0..chunks {
+            let mut checksum = [0u8; 4];
+            self.meta.read_exact(&mut checksum)
+                .map_err(|_| StorageError::Corrupt { block_id: self.block_id.to_string(), chunk: self.chunk + index })?;
+            checksums.push(u32::from_be_bytes(checksum));
+        }
+        checksum::verify(&data, &checksums).map_err(|chunk| StorageError::Corrupt { block_id: self.block_id.to_string(), chunk: self.chunk + chunk })?;
+        self.chunk += chunks;
+        Ok(Some((data, checksums)))
+    }
+}
+
+fn write_meta_header(meta: &mut impl Write, gen_stamp: u64) -> io::Result<()> {
+    meta.write_all(&META_VERSION.to_be_bytes())?;
+    meta.write_all(&(BYTES_PER_CHECKSUM as u32).to_be_bytes())?;
+    meta.write_all(&gen_stamp.to_be_bytes())
+}
+
+// write_legacy_meta computes the checksums of a block written before checksums existed, reading it a packet at a time,
+// such a block predates generation stamps as well and gets generation stamp 0; the meta file is written in the in-progress directory
+// and renamed into place once synced, so a crash never leaves a torn one next to the block (recover deletes the leftover)
+fn write_legacy_meta(data_dir: &Path, block_id: &BlockId) -> io::Result<()> {
+    let _lock = lock_block(block_id);
+    if meta_path(data_dir, block_id).exists() {
+        return Ok(());
+    }
+    let mut data = BufReader::new(File::open(block_path(data_dir, block_id))?);
+    let in_progress = in_progress_dir(data_dir);
+    fs::create_dir_all(&in_progress)?;
+    let temp = in_progress.join(in_progress_meta_name(&in_progress_name(block_id)));
+    let written = (|| {
+        let mut meta = BufWriter::new(File::options().write(true).create_new(true).open(&temp)?);
+        write_meta_header(&mut meta, 0)?;
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        loop {
+            packet.clear();
+            (&mut data).take(PACKET_SIZE as u64).read_to_end(&mut packet)?;
+            if packet.is_empty() {
+                break;
+            }
+            for checksum in checksum::chunk_checksums(&packet) {
+                meta.write_all(&checksum.to_be_bytes())?;
+            }
+        }
+        meta.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp, meta_path(data_dir, block_id))?;
+        sync_dir(&finalized_dir(data_dir))
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+// write_block stores a whole block and its checksums in one go
+pub fn write_block(data_dir: &Path, block_id: &BlockId, gen_stamp: u64, data: &[u8], checksums: &[u32]) -> Result<(), StorageError> {
+    let mut writer = BlockWriter::create(data_dir, block_id, gen_stamp)?;
+    writer.write_packet(data, checksums)?;
+    writer.finish()?;
+    Ok(())
+}
+
+// read_range returns the chunks of a block holding length bytes from offset (length 0 reading to the end of the block)
+// and their checksums after verifying one against the other, together with the offset in the block of the first of them;
+// a replica older than gen_stamp is refused
+pub fn read_range(data_dir: &Path, block_id: &BlockId, gen_stamp: u64, offset: u64, length: u64) -> Result<(u64, Vec<u8>, Vec<u32>), StorageError> {
+    let mut reader = BlockReader::open(data_dir, block_id)?;
+    reader.check_gen_stamp(gen_stamp)?;
+    let start = reader.seek_range(offset, length)?;
+    let (mut data, mut checksums) = (Vec::new(), Vec::new());
+    while let Some((packet, packet_checksums)) = reader.next_packet(PACKET_SIZE / BYTES_PER_CHECKSUM)? {
+        data.extend_from_slice(&packet);
+        checksums.extend_from_slice(&packet_checksums);
+    }
+    Ok((start, data, checksums))
+}
+
+// read_gen_stamp returns the generation stamp a finalized block was stored with
+pub fn read_gen_stamp(data_dir: &Path, block_id: &BlockId) -> Result<u64, StorageError> {
+    Ok(BlockReader::open(data_dir, block_id)?.gen_stamp())
+}
+
+// verify_block tells whether a finalized block reads back cleanly against its meta file from start to end
+pub fn verify_block(data_dir: &Path, block_id: &BlockId) -> bool {
+    reads_cleanly(&finalized_dir(data_dir), block_id.as_str(), block_id)
+}
+
+// Deletion is what delete_block did with a block
+#[derive(Debug, PartialEq)]
+pub enum Deletion {
+    Deleted,
+    // the block was not there
+    Missing,
+    // the replica was stored with this generation stamp, newer than the one the caller meant, and is kept
+    Newer(u64),
+}
+
+// delete_block removes the block and its meta file unless the replica is newer than gen_stamp; it holds the block's lock,
+// so a writer finalizing a newer replica either finishes before the stamp is checked or starts after the files are gone
+pub fn delete_block(data_dir: &Path, block_id: &BlockId, gen_stamp: u64) -> io::Result<Deletion> {
+    let _lock = lock_block(block_id);
+    if let Some(stored) = stored_gen_stamp(data_dir, block_id)?.filter(|stored| *stored > gen_stamp) {
+        return Ok(Deletion::Newer(stored));
+    }
+    let existed = match fs::remove_file(block_path(data_dir, block_id)) {
+        Ok(_) => true,
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+    match fs::remove_file(meta_path(data_dir, block_id)) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(if existed { Deletion::Deleted } else { Deletion::Missing })
+}
+
+// stored_gen_stamp reads the generation stamp from the block's meta file without writing a legacy one, 0 for a block without one,
+// None when the block is gone or its meta file cannot be read, which is no reason to keep it
+fn stored_gen_stamp(data_dir: &Path, block_id: &BlockId) -> io::Result<Option<u64>> {
+    let data = match File::open(block_path(data_dir, block_id)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match File::open(meta_path(data_dir, block_id)) {
+        Ok(meta) => Ok(BlockReader::from_files(block_id, data, meta).ok().map(|reader| reader.gen_stamp())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Some(0)),
+        Err(e) => Err(e),
+    }
+}
+
+// Recovery counts what recover found in a data directory at startup
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovery {
+    // blocks moved from the top of the data directory, where they lived before the finalized directory existed
+    pub migrated: usize,
+    // blocks left in the in-progress directory by a crash that their writer synced whole and that matched their checksums, now finalized
+    pub recovered: usize,
+    // blocks left in the in-progress directory that were never synced, torn, corrupt or already finalized, now deleted
+    pub discarded: usize,
+}
+
+// recover Exhaustive Explanation:
+//    1. Create the finalized and in-progress directories if they are missing
+//    2. Move blocks and meta files sitting directly in the data directory into the finalized directory
+//    3. For every block left in the in-progress directory, look for its meta file there or, if a crash hit between the two renames
+//       of finalize, in the finalized directory
+//    4. Finalize the block if no finalized copy exists, its writer synced it at its current length and it reads back cleanly
+//       against its checksums, otherwise delete it
+//    5. Delete the meta files and synced markers left in the in-progress directory whose block is gone
+pub fn recover(data_dir: &Path) -> io::Result<Recovery> {
+    let mut recovery = Recovery::default();
+    let (in_progress, finalized) = (in_progress_dir(data_dir), finalized_dir(data_dir));
+    fs::create_dir_all(&in_progress)?;
+    fs::create_dir_all(&finalized)?;
+
+    for (block_id, path) in block_files(data_dir)? {
+        fs::rename(path, block_path(data_dir, &block_id))?;
+        match fs::rename(data_dir.join(meta_name(&block_id)), meta_path(data_dir, &block_id)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        recovery.migrated += 1;
+    }
+
+    for (block_id, name) in in_progress_blocks(&in_progress)? {
+        let meta = in_progress.join(in_progress_meta_name(&name));
+        let finalized_meta = meta_path(data_dir, &block_id);
+        if !meta.exists() && finalized_meta.exists() && !block_path(data_dir, &block_id).exists() {
+            fs::rename(&finalized_meta, &meta)?;
+        }
+        if !block_path(data_dir, &block_id).exists() && meta.exists() && synced_whole(&in_progress, &name) && reads_cleanly(&in_progress, &name, &block_id) {
+            finalize(data_dir, &block_id, &name)?;
+            recovery.recovered += 1;
+        } else {
+            fs::remove_file(in_progress.join(&name))?;
+            let _ = fs::remove_file(meta);
+            recovery.discarded += 1;
+        }
+    }
+
+    for entry in fs::read_dir(&in_progress)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == META_EXTENSION || extension == SYNCED_EXTENSION) {
+            fs::remove_file(path)?;
+        }
+    }
+    sync_dir(&finalized)?;
+    sync_dir(&in_progress)?;
+    Ok(recovery)
+}
+
+// block_files lists the files in dir named by a block ID
+fn block_files(dir: &Path) -> io::Result<Vec<(BlockId, PathBuf)>> {
+    let mut blocks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(block_id) = entry.file_name().to_str().and_then(|name| BlockId::parse(name).ok()) {
+            if entry.file_type()?.is_file() {
+                blocks.push((block_id, entry.path()));
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+// in_progress_blocks lists the blocks in the in-progress directory with the name each was written under,
+// <block_id>.<nonce> or, for a block left there by an older data node, <block_id>
+fn in_progress_blocks(dir: &Path) -> io::Result<Vec<(BlockId, String)>> {
+    let mut blocks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if name.ends_with(&format!(".{}", META_EXTENSION)) || name.ends_with(&format!(".{}", SYNCED_EXTENSION)) {
+            continue;
+        }
+        if let Ok(block_id) = BlockId::parse(name.split_once('.').map_or(name.as_str(), |(id, _)| id)) {
+            if entry.file_type()?.is_file() {
+                blocks.push((block_id, name));
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+// synced_whole tells whether the writer of the block named name in dir synced it at the length it has now
+fn synced_whole(dir: &Path, name: &str) -> bool {
+    let synced = fs::read(dir.join(synced_name(name))).ok().and_then(|length| length.try_into().ok()).map(u64::from_be_bytes);
+    let length = fs::metadata(dir.join(name)).map(|metadata| metadata.len()).ok();
+    synced.is_some() && synced == length
+}
+
+// reads_cleanly tells whether the block named name in dir and its meta file match chunk for chunk, a meta file without checksums for
+// the whole block means the block was torn
+fn reads_cleanly(dir: &Path, name: &str, block_id: &BlockId) -> bool {
+    let read = || -> Result<(), StorageError> {
+        let data = File::open(dir.join(name))?;
+        let meta = File::open(dir.join(in_progress_meta_name(name)))?;
+        let mut reader = BlockReader::from_files(block_id, data, meta)?;
+        while reader.next_packet(PACKET_SIZE / BYTES_PER_CHECKSUM)?.is_some() {}
+        Ok(())
+    };
+    read().is_ok()
+}
@@ -0,0 +1,109 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use crate::nnlib::SerializableNodeAddress;
+
+// EditOp is a single namespace mutation, NameNodeState::apply knows how to replay each one
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum EditOp {
+    CreateFile {
+        file_name: String,
+        #[serde(default)]
+        replication: u32,
+        #[serde(default)]
+        block_size: u32,
+        // a file created by AssignBlocksForFile, whose blocks are only written after the edit is logged
+        #[serde(default)]
+        under_construction: bool,
+    },
+    AddBlock {
+        file_name: String,
+        block_id: String,
+        length: u64,
+        data_nodes: Vec<SerializableNodeAddress>,
+        // edits logged before generation stamps existed read as 0
+        #[serde(default)]
+        gen_stamp: u64,
+    },
+    RemoveDataNode { data_node_id: String },
+    AddReplicas { block_id: String, data_nodes: Vec<SerializableNodeAddress> },
+    RemoveReplica { block_id: String, data_node_id: String },
+    RegisterDataNode { data_node: SerializableNodeAddress, storage_id: String, capacity: u64 },
+    Mkdirs { path: String },
+    Delete { path: String, recursive: bool },
+    Rename { src: String, dst: String, overwrite: bool },
+    SetAccessTime { file_name: String, atime: u64 },
+    // the client wrote every block of a file under construction, each with the length given
+    CompleteFile { file_name: String, block_lengths: Vec<(String, u64)> },
+}
+
+// EditLogEntry is one line of the edit log, txid increases by one per op and lets replay skip ops already folded into the snapshot,
+// timestamp is when the op was applied (milliseconds since the unix epoch) so replay reproduces the same modification times
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EditLogEntry {
+    pub txid: u64,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub op: EditOp,
+}
+
+// EditLog is the append-only write-ahead log of namespace ops, one JSON entry per line, that sits on top of the last state snapshot
+#[derive(Debug)]
+pub struct EditLog {
+    file: File,
+    entries: usize,
+}
+
+impl EditLog {
+    // open opens the log at path for appending, creating it if needed
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let entries = Self::read(path)?.len();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EditLog { file, entries })
+    }
+
+    // read returns the entries in the log at path, a missing log has no entries
+    // reading stops at the first line that does not parse, which is a torn append from a crash
+    pub fn read(path: &Path) -> io::Result<Vec<EditLogEntry>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    // append writes the entries to the end of the log and fsyncs before returning, so they are durable before they are applied
+    pub fn append(&mut self, entries: &[EditLogEntry]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            buf.push(b'\n');
+        }
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.entries += entries.len();
+        Ok(())
+    }
+
+    // truncate empties the log once its entries have been folded into a snapshot
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.entries = 0;
+        Ok(())
+    }
+
+    // is_empty reports whether anything was appended since the last checkpoint
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+}
@@ -1,17 +1,21 @@
 use clap::{Arg, Command};
 mod nnlib;
+mod editlog;
+mod namespace;
+mod placement;
 pub mod namenode {
     tonic::include_proto!("namenode");
 }
-use namenode::name_node_server::{NameNode, NameNodeServer};
-use crate::nnlib::{NameNodeState, NameNodeService, SerializableNodeAddress};
+use namenode::name_node_server::NameNodeServer;
+use crate::nnlib::{NameNodeState, NameNodeService, ReplicationLimits, SerializableNodeAddress};
+use crate::editlog::EditLog;
+use crate::placement::PlacementPolicy;
 use tonic::transport::Server;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::sync::RwLock;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::fs;
-use serde_json;
+use std::path::PathBuf;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = Command::new("HDFS Namenode")
@@ -45,12 +49,71 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .value_name("DATA_NODES")
                 .help("List of data nodes in cluster")
         )
+        .arg(
+            Arg::new("checkpointInterval")
+                .short('c')
+                .long("checkpoint-interval")
+                .value_name("SECONDS")
+                .help("Sets how often the edit log is folded into the state snapshot")
+        )
+        .arg(
+            Arg::new("heartbeatInterval")
+                .long("heartbeat-interval")
+                .value_name("SECONDS")
+                .help("Sets how often data nodes are expected to heartbeat")
+        )
+        .arg(
+            Arg::new("replicationInterval")
+                .long("replication-interval")
+                .value_name("SECONDS")
+                .help("Sets how often the replication monitor scans for under- and over-replicated blocks")
+        )
+        .arg(
+            Arg::new("maxReplications")
+                .long("max-replications")
+                .value_name("COUNT")
+                .help("Sets how many blocks the replication monitor copies per scan")
+        )
+        .arg(
+            Arg::new("maxDeletions")
+                .long("max-deletions")
+                .value_name("COUNT")
+                .help("Sets how many surplus replicas the replication monitor deletes per scan")
+        )
+        .arg(
+            Arg::new("maxStreamsPerNode")
+                .long("max-streams-per-node")
+                .value_name("COUNT")
+                .help("Sets how many copies a data node takes part in per scan")
+        )
+        .arg(
+            Arg::new("deadNodeHeartbeats")
+                .long("dead-node-heartbeats")
+                .value_name("COUNT")
+                .help("Sets how many heartbeats a data node may miss before it is declared dead and phoenixed")
+        )
+        .arg(
+            Arg::new("placementPolicy")
+                .long("placement-policy")
+                .value_name("POLICY")
+                .help("Sets how replica targets are chosen: random-with-capacity or random")
+        )
         .get_matches();
 
     let port = matches.get_one::<String>("port").map(String::as_str).unwrap_or("8080");
     let block_size = matches.get_one::<String>("blockSize").map(String::as_str).unwrap_or("100");
     let repl_factor = matches.get_one::<String>("replFactor").map(String::as_str).unwrap_or("3");
     let data_nodes = matches.get_one::<String>("dataNodes").map(String::as_str).unwrap_or("localhost:8080,localhost:8081,localhost:8082");
+    let checkpoint_interval = matches.get_one::<String>("checkpointInterval").map(String::as_str).unwrap_or("60");
+    let heartbeat_interval: u64 = matches.get_one::<String>("heartbeatInterval").map(String::as_str).unwrap_or("3").parse().unwrap();
+    let dead_node_heartbeats: u64 = matches.get_one::<String>("deadNodeHeartbeats").map(String::as_str).unwrap_or("10").parse().unwrap();
+    let replication_interval: u64 = matches.get_one::<String>("replicationInterval").map(String::as_str).unwrap_or("3").parse().unwrap();
+    let placement_policy: PlacementPolicy = matches.get_one::<String>("placementPolicy").map(String::as_str).unwrap_or("random-with-capacity").parse().unwrap();
+    let replication_limits = ReplicationLimits {
+        max_replications: matches.get_one::<String>("maxReplications").map(String::as_str).unwrap_or("10").parse().unwrap(),
+        max_deletions: matches.get_one::<String>("maxDeletions").map(String::as_str).unwrap_or("100").parse().unwrap(),
+        max_streams_per_node: matches.get_one::<String>("maxStreamsPerNode").map(String::as_str).unwrap_or("2").parse().unwrap(),
+    };
 
     println!("Port: {}", port);
     println!("Block Size: {}", block_size);
@@ -62,24 +125,89 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut state = NameNodeState::new(
         block_size.parse().unwrap(),
         repl_factor.parse().unwrap(),
-        data_nodes.into_iter().map(|(addr, _)| (addr.into(), false)).collect()
+        data_nodes
     );
 
-    let state_file = format!("{}.state", port);
-    if let Ok(state_str) = fs::read_to_string(&state_file) {
-        if let Ok(loaded_state) = serde_json::from_str::<NameNodeState>(&state_str) {
-            state = loaded_state;
-        }
+    let state_file = PathBuf::from(format!("{}.state", port));
+    if let Ok(loaded_state) = NameNodeState::load(&state_file) {
+        state = loaded_state;
+    }
+    let edits_file = PathBuf::from(format!("{}.edits", port));
+    let replayed = state.replay(EditLog::read(&edits_file)?);
+    println!("Replayed {} edits from {}", replayed, edits_file.display());
+    if state.block_size == 0 {
+        return Err("block size must be greater than 0".into());
     }
+    state.reset_last_seen(nnlib::now_millis());
+    state.placement_policy = placement_policy;
+
+    let service = NameNodeService {
+        state: Arc::new(RwLock::new(state)),
+        state_file: Some(state_file),
+        edit_log: Some(Arc::new(Mutex::new(EditLog::open(&edits_file)?))),
+        ..Default::default()
+    };
+    // fold the replayed edits (and any torn tail of the log) into a fresh snapshot before taking new edits
+    service.checkpoint().await?;
+
+    let checkpointer = service.clone();
+    let checkpoint_interval = tokio::time::Duration::from_secs(checkpoint_interval.parse().unwrap());
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(checkpoint_interval).await;
+            if checkpointer.has_pending_edits() {
+                if let Err(e) = checkpointer.checkpoint().await {
+                    println!("Checkpoint failed: {}", e);
+                }
+            }
+        }
+    });
+
+    // replication monitor: keeps every block at repl_factor live replicas, a pass at a time
+    let replicator = service.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(replication_interval)).await;
+            match replicator.replicate_and_trim(&replication_limits).await {
+                Ok((0, 0)) => {}
+                Ok((replicated, deleted)) => println!("Replication monitor re-replicated {} blocks and deleted {} surplus replicas", replicated, deleted),
+                Err(e) => println!("Replication monitor failed: {}", e),
+            }
+        }
+    });
+
+    // lease monitor: files left under construction by a client that went away are abandoned
+    let lease_monitor = service.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(replication_interval)).await;
+            match lease_monitor.abandon_expired_files(nnlib::now_millis()).await {
+                Ok(0) => {}
+                Ok(abandoned) => println!("Lease monitor abandoned {} files left under construction", abandoned),
+                Err(e) => println!("Lease monitor failed: {}", e),
+            }
+        }
+    });
 
     let server = Server::builder()
-        .add_service(NameNodeServer::new(NameNodeService { state: Arc::new(RwLock::new(state)) }))
-        .serve(SocketAddr::from_str(&nn_addr).unwrap());
+        .add_service(NameNodeServer::new(service.clone()))
+        .serve_with_shutdown(SocketAddr::from_str(&nn_addr).unwrap(), async {
+            tokio::signal::ctrl_c().await.ok();
+        });
 
+    // dead node monitor: a data node that misses dead_node_heartbeats heartbeats in a row is declared dead and phoenixed
+    let monitor = service.clone();
+    let dead_node_timeout_ms = heartbeat_interval * dead_node_heartbeats * 1000;
     tokio::spawn(async move {
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            println!("Still waiting for requests...");
+            tokio::time::sleep(tokio::time::Duration::from_secs(heartbeat_interval)).await;
+            for data_node in monitor.dead_data_nodes(dead_node_timeout_ms).await {
+                println!("Data node {} missed {} heartbeats, phoenixing it", data_node.id(), dead_node_heartbeats);
+                match monitor.phoenix(data_node.into()).await {
+                    Ok(result) => println!("{}", result.message),
+                    Err(e) => println!("Phoenixing failed: {}", e),
+                }
+            }
         }
     });
 
@@ -88,15 +216,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Err(e) => println!("Server error: {}", e),
     }
 
+    match service.checkpoint().await {
+        Ok(_) => println!("State checkpointed"),
+        Err(e) => println!("Failed to checkpoint state: {}", e),
+    }
+
     Ok(())
 }
 
-fn parse_data_nodes(data_nodes: &str) -> Vec<(SerializableNodeAddress, bool)> {
+fn parse_data_nodes(data_nodes: &str) -> Vec<(SerializableNodeAddress, u64)> {
     data_nodes.split(",").map(|node| {
         let parts: Vec<&str> = node.split(":").collect();
         (SerializableNodeAddress {
             host: parts[0].to_string(),
             port: parts[1].parse().unwrap(),
-        }, false)
+        }, 0)
     }).collect()
 }
@@ -0,0 +1,395 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use tonic::Status;
+
+// FileINode is a file in the namespace, the list of its blocks in file order plus its metadata,
+// times are milliseconds since the unix epoch
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileINode {
+    pub blocks: Vec<String>,
+    #[serde(default)]
+    pub replication: u32,
+    #[serde(default)]
+    pub block_size: u32,
+    #[serde(default)]
+    pub mtime: u64,
+    #[serde(default)]
+    pub atime: u64,
+    // set from AssignBlocksForFile until the client completes the file, its blocks have no length or replicas recorded before that
+    #[serde(default)]
+    pub under_construction: bool,
+}
+
+// DirectoryINode is a directory in the namespace, its children keyed by name so listings come out sorted,
+// mtime changes whenever a child is added or removed
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DirectoryINode {
+    pub children: BTreeMap<String, INode>,
+    #[serde(default)]
+    pub mtime: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum INode {
+    File(FileINode),
+    Directory(DirectoryINode),
+}
+
+impl INode {
+    pub fn is_directory(&self) -> bool {
+        matches!(self, INode::Directory(_))
+    }
+
+    // block_ids returns every block of the inode, walking the whole subtree of a directory
+    pub fn block_ids(&self) -> Vec<String> {
+        match self {
+            INode::File(file) => file.blocks.clone(),
+            INode::Directory(dir) => dir.children.values().flat_map(INode::block_ids).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamespaceError {
+    InvalidPath(String),
+    NotFound(String),
+    AlreadyExists(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    DirectoryNotEmpty(String),
+}
+
+impl fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamespaceError::InvalidPath(reason) => write!(f, "Invalid path: {}", reason),
+            NamespaceError::NotFound(path) => write!(f, "{} does not exist", path),
+            NamespaceError::AlreadyExists(path) => write!(f, "{} already exists", path),
+            NamespaceError::NotADirectory(path) => write!(f, "{} is not a directory", path),
+            NamespaceError::IsADirectory(path) => write!(f, "{} is a directory", path),
+            NamespaceError::DirectoryNotEmpty(path) => write!(f, "{} is not empty", path),
+        }
+    }
+}
+
+impl From<NamespaceError> for Status {
+    fn from(e: NamespaceError) -> Self {
+        match e {
+            NamespaceError::InvalidPath(_) => Status::invalid_argument(e.to_string()),
+            NamespaceError::NotFound(_) => Status::not_found(e.to_string()),
+            NamespaceError::AlreadyExists(_) => Status::already_exists(e.to_string()),
+            NamespaceError::NotADirectory(_)
+            | NamespaceError::IsADirectory(_)
+            | NamespaceError::DirectoryNotEmpty(_) => Status::failed_precondition(e.to_string()),
+        }
+    }
+}
+
+// normalize_path turns a client supplied path into its canonical "/a/b/c" form:
+//     - relative paths are taken from the root, so "a/b" is "/a/b"
+//     - repeated and trailing slashes are dropped
+//     - "." and ".." components and control characters are rejected rather than resolved
+pub fn normalize_path(path: &str) -> Result<String, NamespaceError> {
+    Ok(format!("/{}", components(path)?.join("/")))
+}
+
+// components splits a path into its validated names, the root has none
+fn components(path: &str) -> Result<Vec<&str>, NamespaceError> {
+    if path.is_empty() {
+        return Err(NamespaceError::InvalidPath("path is empty".to_string()));
+    }
+    let mut names = Vec::new();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if name == "." || name == ".." {
+            return Err(NamespaceError::InvalidPath(format!("{} may not contain \"{}\"", path, name)));
+        }
+        if name.chars().any(char::is_control) {
+            return Err(NamespaceError::InvalidPath(format!("{:?} contains control characters", path)));
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
+// Namespace is the inode tree of the file system, root is always a directory
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Namespace {
+    root: INode,
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Namespace { root: INode::Directory(DirectoryINode::default()) }
+    }
+}
+
+impl Namespace {
+    // get looks up the inode at path
+    pub fn get(&self, path: &str) -> Result<&INode, NamespaceError> {
+        let names = components(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Ok(&self.root);
+        };
+        self.dir(parents)?.children.get(*name).ok_or_else(|| NamespaceError::NotFound(normalized(&names)))
+    }
+
+    pub fn get_file(&self, path: &str) -> Result<&FileINode, NamespaceError> {
+        match self.get(path)? {
+            INode::File(file) => Ok(file),
+            INode::Directory(_) => Err(NamespaceError::IsADirectory(normalize_path(path)?)),
+        }
+    }
+
+    // check_create verifies that a file could be created at path: it must not exist yet and no ancestor may be a file
+    pub fn check_create(&self, path: &str) -> Result<(), NamespaceError> {
+        let names = components(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Err(NamespaceError::AlreadyExists("/".to_string()));
+        };
+        let mut dir = self.root_dir();
+        for (depth, parent) in parents.iter().enumerate() {
+            match dir.children.get(*parent) {
+                Some(INode::Directory(child)) => dir = child,
+                Some(INode::File(_)) => return Err(NamespaceError::NotADirectory(normalized(&names[..=depth]))),
+                None => return Ok(()),
+            }
+        }
+        if dir.children.contains_key(*name) {
+            return Err(NamespaceError::AlreadyExists(normalized(&names)));
+        }
+        Ok(())
+    }
+
+    // create_file adds an empty file at path created at now, creating any missing parent directories
+    pub fn create_file(&mut self, path: &str, replication: u32, block_size: u32, under_construction: bool, now: u64) -> Result<(), NamespaceError> {
+        self.check_create(path)?;
+        let names = components(path)?;
+        let (name, parents) = names.split_last().expect("check_create rejects the root");
+        let dir = self.mkdirs_components(parents, now)?;
+        let file = FileINode { blocks: Vec::new(), replication, block_size, mtime: now, atime: now, under_construction };
+        dir.children.insert(name.to_string(), INode::File(file));
+        dir.mtime = now;
+        Ok(())
+    }
+
+    // add_block appends block_id to the blocks of the file at path, modifying it at now
+    pub fn add_block(&mut self, path: &str, block_id: String, now: u64) -> Result<(), NamespaceError> {
+        let file = self.get_file_mut(path)?;
+        file.blocks.push(block_id);
+        file.mtime = now;
+        Ok(())
+    }
+
+    // complete_file marks the file at path as written in full at now
+    pub fn complete_file(&mut self, path: &str, now: u64) -> Result<(), NamespaceError> {
+        let file = self.get_file_mut(path)?;
+        file.under_construction = false;
+        file.mtime = now;
+        Ok(())
+    }
+
+    // files_under_construction returns the path and mtime of every file still under construction
+    pub fn files_under_construction(&self) -> Vec<(String, u64)> {
+        fn walk(path: &str, dir: &DirectoryINode, files: &mut Vec<(String, u64)>) {
+            for (name, child) in &dir.children {
+                let child_path = format!("{}/{}", path, name);
+                match child {
+                    INode::File(file) if file.under_construction => files.push((child_path, file.mtime)),
+                    INode::File(_) => {}
+                    INode::Directory(child) => walk(&child_path, child, files),
+                }
+            }
+        }
+        let mut files = Vec::new();
+        walk("", self.root_dir(), &mut files);
+        files
+    }
+
+    // set_atime records that the file at path was read at atime
+    pub fn set_atime(&mut self, path: &str, atime: u64) -> Result<(), NamespaceError> {
+        self.get_file_mut(path)?.atime = atime;
+        Ok(())
+    }
+
+    fn get_file_mut(&mut self, path: &str) -> Result<&mut FileINode, NamespaceError> {
+        let names = components(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Err(NamespaceError::IsADirectory("/".to_string()));
+        };
+        match self.dir_mut(parents)?.children.get_mut(*name) {
+            Some(INode::File(file)) => Ok(file),
+            Some(INode::Directory(_)) => Err(NamespaceError::IsADirectory(normalized(&names))),
+            None => Err(NamespaceError::NotFound(normalized(&names))),
+        }
+    }
+
+    // check_mkdirs verifies that mkdirs could create path, no component of it may be an existing file
+    pub fn check_mkdirs(&self, path: &str) -> Result<(), NamespaceError> {
+        let names = components(path)?;
+        let mut dir = self.root_dir();
+        for (depth, name) in names.iter().enumerate() {
+            match dir.children.get(*name) {
+                Some(INode::Directory(child)) => dir = child,
+                Some(INode::File(_)) => return Err(NamespaceError::NotADirectory(normalized(&names[..=depth]))),
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    // mkdirs creates the directory at path along with any missing parents at now, an existing directory is not an error
+    pub fn mkdirs(&mut self, path: &str, now: u64) -> Result<(), NamespaceError> {
+        let names = components(path)?;
+        self.mkdirs_components(&names, now)?;
+        Ok(())
+    }
+
+    // list returns the children of the directory at path in name order, or the file itself if path is a file
+    pub fn list(&self, path: &str) -> Result<Vec<(String, &INode)>, NamespaceError> {
+        let names = components(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Ok(self.root_dir().children.iter().map(|(name, inode)| (name.clone(), inode)).collect());
+        };
+        match self.dir(parents)?.children.get(*name) {
+            Some(INode::Directory(dir)) => Ok(dir.children.iter().map(|(name, inode)| (name.clone(), inode)).collect()),
+            Some(file) => Ok(vec![(name.to_string(), file)]),
+            None => Err(NamespaceError::NotFound(normalized(&names))),
+        }
+    }
+
+    // check_delete verifies that path could be deleted, a non-empty directory needs recursive and the root is never deleted
+    pub fn check_delete(&self, path: &str, recursive: bool) -> Result<&INode, NamespaceError> {
+        if components(path)?.is_empty() {
+            return Err(NamespaceError::InvalidPath("the root directory cannot be deleted".to_string()));
+        }
+        let inode = self.get(path)?;
+        if let INode::Directory(dir) = inode {
+            if !recursive && !dir.children.is_empty() {
+                return Err(NamespaceError::DirectoryNotEmpty(normalize_path(path)?));
+            }
+        }
+        Ok(inode)
+    }
+
+    // delete removes the file or directory at path at now and returns the removed inode
+    pub fn delete(&mut self, path: &str, recursive: bool, now: u64) -> Result<INode, NamespaceError> {
+        self.check_delete(path, recursive)?;
+        let names = components(path)?;
+        let (name, parents) = names.split_last().expect("check_delete rejects the root");
+        let dir = self.dir_mut(parents)?;
+        dir.mtime = now;
+        Ok(dir.children.remove(*name).expect("check_delete found the inode"))
+    }
+
+    // check_rename verifies that src could be moved to dst and returns the inode dst would replace, if any:
+    //     - src must exist and may not be the root
+    //     - a directory cannot be moved into its own subtree
+    //     - the parent of dst must be an existing directory
+    //     - an existing dst is only replaced with overwrite, by the same kind of inode, and a directory only when it is empty
+    pub fn check_rename(&self, src: &str, dst: &str, overwrite: bool) -> Result<Option<&INode>, NamespaceError> {
+        let src_names = components(src)?;
+        let dst_names = components(dst)?;
+        if src_names.is_empty() {
+            return Err(NamespaceError::InvalidPath("the root directory cannot be renamed".to_string()));
+        }
+        let src_inode = self.get(src)?;
+        if src_names == dst_names {
+            return Ok(None);
+        }
+        if dst_names.starts_with(&src_names) {
+            return Err(NamespaceError::InvalidPath(format!("cannot move {} into its own subtree {}", normalized(&src_names), normalized(&dst_names))));
+        }
+        let Some((name, parents)) = dst_names.split_last() else {
+            return Err(NamespaceError::AlreadyExists("/".to_string()));
+        };
+        let Some(dst_inode) = self.dir(parents)?.children.get(*name) else {
+            return Ok(None);
+        };
+        if !overwrite {
+            return Err(NamespaceError::AlreadyExists(normalized(&dst_names)));
+        }
+        match (src_inode, dst_inode) {
+            (INode::File(_), INode::File(_)) => Ok(Some(dst_inode)),
+            (INode::Directory(_), INode::Directory(dir)) if dir.children.is_empty() => Ok(Some(dst_inode)),
+            (INode::Directory(_), INode::Directory(_)) => Err(NamespaceError::DirectoryNotEmpty(normalized(&dst_names))),
+            (INode::File(_), INode::Directory(_)) => Err(NamespaceError::IsADirectory(normalized(&dst_names))),
+            (INode::Directory(_), INode::File(_)) => Err(NamespaceError::NotADirectory(normalized(&dst_names))),
+        }
+    }
+
+    // rename moves the inode at src to dst at now and returns the inode it replaced, if any
+    pub fn rename(&mut self, src: &str, dst: &str, overwrite: bool, now: u64) -> Result<Option<INode>, NamespaceError> {
+        self.check_rename(src, dst, overwrite)?;
+        let src_names = components(src)?;
+        let dst_names = components(dst)?;
+        if src_names == dst_names {
+            return Ok(None);
+        }
+        let (src_name, src_parents) = src_names.split_last().expect("check_rename rejects the root");
+        let (dst_name, dst_parents) = dst_names.split_last().expect("check_rename rejects the root");
+        let src_dir = self.dir_mut(src_parents)?;
+        src_dir.mtime = now;
+        let inode = src_dir.children.remove(*src_name).expect("check_rename found the source");
+        let dst_dir = self.dir_mut(dst_parents)?;
+        dst_dir.mtime = now;
+        Ok(dst_dir.children.insert(dst_name.to_string(), inode))
+    }
+
+    fn root_dir(&self) -> &DirectoryINode {
+        match &self.root {
+            INode::Directory(dir) => dir,
+            INode::File(_) => unreachable!("the root is always a directory"),
+        }
+    }
+
+    fn root_dir_mut(&mut self) -> &mut DirectoryINode {
+        match &mut self.root {
+            INode::Directory(dir) => dir,
+            INode::File(_) => unreachable!("the root is always a directory"),
+        }
+    }
+
+    fn dir(&self, names: &[&str]) -> Result<&DirectoryINode, NamespaceError> {
+        let mut dir = self.root_dir();
+        for (depth, name) in names.iter().enumerate() {
+            dir = match dir.children.get(*name) {
+                Some(INode::Directory(child)) => child,
+                Some(INode::File(_)) => return Err(NamespaceError::NotADirectory(normalized(&names[..=depth]))),
+                None => return Err(NamespaceError::NotFound(normalized(&names[..=depth]))),
+            };
+        }
+        Ok(dir)
+    }
+
+    fn dir_mut(&mut self, names: &[&str]) -> Result<&mut DirectoryINode, NamespaceError> {
+        let mut dir = self.root_dir_mut();
+        for (depth, name) in names.iter().enumerate() {
+            dir = match dir.children.get_mut(*name) {
+                Some(INode::Directory(child)) => child,
+                Some(INode::File(_)) => return Err(NamespaceError::NotADirectory(normalized(&names[..=depth]))),
+                None => return Err(NamespaceError::NotFound(normalized(&names[..=depth]))),
+            };
+        }
+        Ok(dir)
+    }
+
+    fn mkdirs_components(&mut self, names: &[&str], now: u64) -> Result<&mut DirectoryINode, NamespaceError> {
+        let mut dir = self.root_dir_mut();
+        for (depth, name) in names.iter().enumerate() {
+            if !dir.children.contains_key(*name) {
+                dir.children.insert(name.to_string(), INode::Directory(DirectoryINode { children: BTreeMap::new(), mtime: now }));
+                dir.mtime = now;
+            }
+            dir = match dir.children.get_mut(*name) {
+                Some(INode::Directory(child)) => child,
+                _ => return Err(NamespaceError::NotADirectory(normalized(&names[..=depth]))),
+            };
+        }
+        Ok(dir)
+    }
+}
+
+fn normalized(names: &[&str]) -> String {
+    format!("/{}", names.join("/"))
+}
@@ -1,28 +1,60 @@
+// tonic::Status is large, but it is the error type every RPC helper here hands back
+#![allow(clippy::result_large_err)]
 use tonic::{Request, Response, Status};
-use serde_json;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::nnlib::datanode::data_node_client::DataNodeClient;
-use crate::nnlib::datanode::GetDataRequest;
-mod namenode {
-    tonic::include_proto!("namenode");
-}
+use tokio::sync::{mpsc, RwLock, RwLockWriteGuard};
+use tokio_stream::wrappers::ReceiverStream;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::editlog::{EditLog, EditLogEntry, EditOp};
+use crate::namespace::{normalize_path, INode, Namespace, NamespaceError};
+use crate::placement::PlacementPolicy;
+use rs_dfs::datanode::data_node_client::DataNodeClient;
+use rs_dfs::datanode::{GetDataRequest, PutDataPacket, PipelineAck, DeleteBlocksRequest};
+use rs_dfs::BlockId;
+use rs_dfs::checksum::{self, PACKET_SIZE};
+use rs_dfs::pipeline::pipeline_acks;
+use rs_dfs::read::fetch_range;
+
+use crate::namenode::{ReportedBlock, ReadFileRequest, ReadFileResponse, PreadRequest, PreadResponse, NodeAddress, WriteFileRequest, WriteFileResponse, PhoenixingResult,BlockSizeRequest, BlockSizeResponse, AssignBlocksForFileRequest, AssignBlocksForFileResponse, CompleteFileRequest, CompleteFileResponse, AbandonFileRequest, AbandonFileResponse, LocatedBlock, GetBlockLocationsRequest, GetBlockLocationsResponse, MkdirsRequest, MkdirsResponse, ListDirectoryRequest, ListDirectoryResponse, DirectoryEntry, DeleteRequest, DeleteResponse, DeleteFileRequest, DeleteFileResponse, RenameRequest, RenameResponse, GetFileInfoRequest, GetFileInfoResponse, FileInfo, RegisterDataNodeRequest, RegisterDataNodeResponse, HeartbeatRequest, HeartbeatResponse, BlockReplicationOutcome, BlockReportRequest, BlockReportResponse, IncrementalBlockReportRequest, IncrementalBlockReportResponse, ReportBadBlocksRequest, ReportBadBlocksResponse, DataNodeCommand, ReplicateCommand, DeleteCommand};
+use crate::namenode::data_node_command::Command;
+use crate::namenode::name_node_server::NameNode;
+// access times are only logged when they move by more than this, so reads do not turn into a stream of edits
+pub const ACCESS_TIME_PRECISION_MS: u64 = 60 * 60 * 1000;
+// how long a command sent to a data node may go unacknowledged before it is given up on
+pub const COMMAND_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+// how many times a delete command is sent before it is dropped
+pub const MAX_COMMAND_ATTEMPTS: u32 = 3;
+// how long a file may stay under construction without a new block before its client is taken to be gone and the file is abandoned
+pub const FILE_LEASE_MS: u64 = 60 * 60 * 1000;
+// a block of a file as (block ID, offset of its first byte in the file, length)
+pub type BlockExtent = (String, u64, u64);
+// packets a streamed block may have in flight between a data node and a slower one
+const STREAM_BUFFER: usize = 4;
 
-mod datanode {
-    tonic::include_proto!("datanode");
+// now_millis is the current time in milliseconds since the unix epoch, the unit of every timestamp in the namespace
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
 }
 
-use crate::namenode::{ReadFileRequest, ReadFileResponse, NodeAddress, WriteFileRequest, WriteFileResponse, PhoenixingResult,BlockSizeRequest, BlockSizeResponse, AssignBlocksForFileRequest, AssignBlocksForFileResponse};
-use crate::namenode::name_node_server::NameNode;
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct SerializableNodeAddress {
     pub host: String,
     pub port: u32,
 }
 
+impl SerializableNodeAddress {
+    // the "host:port" form used as the data node ID and in nodes_left
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
 impl From<NodeAddress> for SerializableNodeAddress {
     fn from(addr: NodeAddress) -> Self {
         SerializableNodeAddress {
@@ -41,32 +73,1106 @@ impl From<SerializableNodeAddress> for NodeAddress {
     }
 }
 
+// DataNodeStats is the usage a data node reported in its last heartbeat, sizes are in bytes
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DataNodeStats {
+    pub capacity: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub active_transfers: u32,
+    pub failed_volumes: u32,
+}
+
+// BlockReportReconciliation is how a full block report differs from what the block map believes the data node holds
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BlockReportReconciliation {
+    // replicas the block map has but the data node did not report with the right length
+    pub missing: Vec<String>,
+    // reported replicas of blocks that belong to no file
+    pub orphaned: Vec<String>,
+    // reported replicas of known blocks the block map does not have yet
+    pub extra: Vec<String>,
+    // reported replicas older than their block's generation stamp, to be deleted
+    pub stale: Vec<String>,
+    // reported replicas of known blocks with a length other than the block's, to be deleted
+    pub wrong_length: Vec<String>,
+}
+
+// ReplicationLimits caps the work one pass of the replication monitor schedules, so recovery does not saturate the cluster
+#[derive(Debug, Clone)]
+pub struct ReplicationLimits {
+    pub max_replications: usize,
+    pub max_deletions: usize,
+    // copies a single data node may take part in per pass, as source or target
+    pub max_streams_per_node: usize,
+}
+
+// ReplicationWork is what one pass of the replication monitor has to do:
+// copies are (block ID, replicas to copy from, targets) and surplus is (block ID, data node ID) replicas to delete
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReplicationWork {
+    pub copies: Vec<(String, Vec<SerializableNodeAddress>, Vec<SerializableNodeAddress>)>,
+    pub surplus: Vec<(String, String)>,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct NameNodeState {
     pub block_size: u32,
     pub repl_factor: u32,
-    pub data_nodes: Vec<(SerializableNodeAddress, bool)>,
-    pub file_name_to_blocks: HashMap<String, Vec<String>>,
+    // every data node with the time of its last heartbeat in milliseconds since the unix epoch, 0 if none was received yet
+    #[serde(deserialize_with = "deserialize_data_nodes")]
+    pub data_nodes: Vec<(SerializableNodeAddress, u64)>,
+    #[serde(default)]
+    pub namespace: Namespace,
     pub block_to_data_node_ids: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub block_lengths: HashMap<String, u64>,
+    // generation stamp of the current version of each block, replicas with an older one are stale
+    #[serde(default)]
+    pub block_gen_stamps: HashMap<String, u64>,
+    // blocks of files under construction, they have no length yet and the replication monitor leaves them alone
+    #[serde(default)]
+    pub blocks_under_construction: HashSet<String>,
+    // the last generation stamp handed out, stamps only ever grow
+    #[serde(default)]
+    pub last_gen_stamp: u64,
     pub id_to_data_nodes: HashMap<String, SerializableNodeAddress>,
+    // storage ID and capacity in bytes each registered data node reported, keyed by data node ID
+    #[serde(default)]
+    pub data_node_storage_ids: HashMap<String, String>,
+    #[serde(default)]
+    pub data_node_capacities: HashMap<String, u64>,
+    // usage from the last heartbeat of each data node, only meaningful while the data node keeps heartbeating so it is not saved
+    #[serde(skip)]
+    pub data_node_stats: HashMap<String, DataNodeStats>,
+    // how replica targets are chosen, set at startup rather than saved
+    #[serde(skip)]
+    pub placement_policy: PlacementPolicy,
+    // orphaned replicas each data node reported in its last full block report, an orphan reported twice in a row is deleted
+    #[serde(skip)]
+    pub reported_orphans: HashMap<String, HashSet<String>>,
+    // txid of the last edit folded into this state, edits up to it are skipped on replay
+    #[serde(default)]
+    pub last_txid: u64,
 }
 
 impl NameNodeState {
-    pub fn new(block_size: u32, repl_factor: u32, data_nodes: Vec<(SerializableNodeAddress, bool)>) -> Self {
+    pub fn new(block_size: u32, repl_factor: u32, data_nodes: Vec<(SerializableNodeAddress, u64)>) -> Self {
         Self { 
             block_size, 
             repl_factor, 
             data_nodes,
-            file_name_to_blocks: HashMap::new(),
+            namespace: Namespace::default(),
             block_to_data_node_ids: HashMap::new(),
+            block_lengths: HashMap::new(),
+            block_gen_stamps: HashMap::new(),
+            blocks_under_construction: HashSet::new(),
+            last_gen_stamp: 0,
             id_to_data_nodes: HashMap::new(),
+            data_node_storage_ids: HashMap::new(),
+            data_node_capacities: HashMap::new(),
+            data_node_stats: HashMap::new(),
+            placement_policy: PlacementPolicy::default(),
+            reported_orphans: HashMap::new(),
+            last_txid: 0,
+        }
+    }
+
+    // choose_data_nodes asks the placement policy for up to repl_factor distinct data nodes to hold a new block written from writer,
+    // the first one is the head of the write pipeline
+    pub fn choose_data_nodes(&self, writer: Option<&str>) -> Vec<SerializableNodeAddress> {
+        self.placement_policy.0.choose_targets(self, writer, self.repl_factor as usize, &[])
+    }
+
+    // choose_targets asks the placement policy for up to count distinct data nodes, skipping the excluded data node IDs
+    pub fn choose_targets(&self, count: usize, excluded: &[String]) -> Vec<SerializableNodeAddress> {
+        self.placement_policy.0.choose_targets(self, None, count, excluded)
+    }
+
+    // block_replicas returns the addresses of the data nodes holding replicas of the block
+    pub fn block_replicas(&self, block_id: &str) -> Vec<SerializableNodeAddress> {
+        self.block_to_data_node_ids.get(block_id)
+            .map(|data_node_ids| data_node_ids.iter()
+                .filter_map(|id| self.id_to_data_nodes.get(id))
+                .cloned()
+                .collect())
+            .unwrap_or_default()
+    }
+
+    // load reads a state snapshot previously written by save
+    pub fn load(path: &Path) -> io::Result<Self> {
+        // snapshots from before the namespace was an inode tree kept a flat map of file names to their blocks
+        #[derive(Deserialize)]
+        struct Snapshot {
+            #[serde(flatten)]
+            state: NameNodeState,
+            #[serde(default)]
+            file_name_to_blocks: HashMap<String, Vec<String>>,
+        }
+        let state_str = fs::read_to_string(path)?;
+        let snapshot: Snapshot = serde_json::from_str(&state_str).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut state = snapshot.state;
+        state.migrate_flat_files(snapshot.file_name_to_blocks, now_millis());
+        Ok(state)
+    }
+
+    // migrate_flat_files rebuilds the inodes of files from a flat map of file names to blocks, creating their parent directories,
+    // a file whose name cannot be placed in the tree is skipped and its blocks are left to show up as orphans
+    fn migrate_flat_files(&mut self, file_name_to_blocks: HashMap<String, Vec<String>>, now: u64) {
+        let mut files: Vec<(String, Vec<String>)> = file_name_to_blocks.into_iter().collect();
+        files.sort();
+        for (file_name, block_ids) in files {
+            let migrated = normalize_path(&file_name).and_then(|path| {
+                self.namespace.create_file(&path, self.repl_factor, self.block_size, false, now)?;
+                for block_id in block_ids {
+                    self.namespace.add_block(&path, block_id, now)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = migrated {
+                println!("Skipping file {} of an old snapshot: {}", file_name, e);
+            }
+        }
+    }
+
+    // save durably replaces the snapshot at path: the state is written to a temp file next to it, fsynced,
+    // and renamed over the old snapshot, so a crash mid-save leaves either the old or the new state, never a torn one
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("state.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // fsync the directory so the rename itself survives a crash
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    // apply performs a single namespace mutation, every change to the namespace goes through here so it can be logged and replayed
+    // callers validate the op against the namespace first, so an error here means the op was logged out of order
+    // timestamp is when the op was logged and becomes the mtime of whatever it changes
+    pub fn apply(&mut self, op: &EditOp, timestamp: u64) -> Result<(), NamespaceError> {
+        match op {
+            EditOp::CreateFile { file_name, replication, block_size, under_construction } => {
+                self.namespace.create_file(file_name, *replication, *block_size, *under_construction, timestamp)?;
+            }
+            EditOp::AddBlock { file_name, block_id, length, data_nodes, gen_stamp } => {
+                self.add_block(file_name, block_id.clone(), *length, data_nodes.clone(), *gen_stamp, timestamp)?;
+            }
+            EditOp::RemoveDataNode { data_node_id } => {
+                for data_node_ids in self.block_to_data_node_ids.values_mut() {
+                    data_node_ids.retain(|id| id != data_node_id);
+                }
+                self.forget_data_node(data_node_id);
+            }
+            EditOp::AddReplicas { block_id, data_nodes } => {
+                // the block may have been deleted while it was being copied, the copy is then an orphan
+                if let Some(data_node_ids) = self.block_to_data_node_ids.get_mut(block_id) {
+                    for data_node in data_nodes {
+                        if !data_node_ids.contains(&data_node.id()) {
+                            data_node_ids.push(data_node.id());
+                        }
+                        self.id_to_data_nodes.insert(data_node.id(), data_node.clone());
+                    }
+                }
+            }
+            EditOp::RemoveReplica { block_id, data_node_id } => {
+                if let Some(data_node_ids) = self.block_to_data_node_ids.get_mut(block_id) {
+                    data_node_ids.retain(|id| id != data_node_id);
+                }
+            }
+            EditOp::RegisterDataNode { data_node, storage_id, capacity } => {
+                self.register_data_node(data_node.clone(), storage_id, *capacity);
+            }
+            EditOp::Mkdirs { path } => {
+                self.namespace.mkdirs(path, timestamp)?;
+            }
+            EditOp::Delete { path, recursive } => {
+                let removed = self.namespace.delete(path, *recursive, timestamp)?;
+                self.remove_blocks(&removed.block_ids());
+            }
+            EditOp::Rename { src, dst, overwrite } => {
+                if let Some(replaced) = self.namespace.rename(src, dst, *overwrite, timestamp)? {
+                    self.remove_blocks(&replaced.block_ids());
+                }
+            }
+            EditOp::SetAccessTime { file_name, atime } => {
+                self.namespace.set_atime(file_name, *atime)?;
+            }
+            EditOp::CompleteFile { file_name, block_lengths } => {
+                self.namespace.complete_file(file_name, timestamp)?;
+                for (block_id, length) in block_lengths {
+                    self.blocks_under_construction.remove(block_id);
+                    self.block_lengths.insert(block_id.clone(), *length);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // replay applies the edit log entries that are newer than the state, returning how many were applied
+    pub fn replay(&mut self, entries: Vec<EditLogEntry>) -> usize {
+        let mut applied = 0;
+        for entry in entries {
+            if entry.txid > self.last_txid {
+                if let Err(e) = self.apply(&entry.op, entry.timestamp) {
+                    println!("Skipping edit {} ({:?}): {}", entry.txid, entry.op, e);
+                }
+                self.last_txid = entry.txid;
+                applied += 1;
+            }
+        }
+        applied
+    }
+
+    // add_block appends block_id to the blocks of file_name and records its length, generation stamp and the data nodes holding its replicas,
+    // a block of a file under construction gets its length when the file is completed
+    pub fn add_block(&mut self, file_name: &str, block_id: String, length: u64, data_nodes: Vec<SerializableNodeAddress>, gen_stamp: u64, now: u64) -> Result<(), NamespaceError> {
+        self.namespace.add_block(file_name, block_id.clone(), now)?;
+        if self.namespace.get_file(file_name)?.under_construction {
+            self.blocks_under_construction.insert(block_id.clone());
+        } else {
+            self.block_lengths.insert(block_id.clone(), length);
+        }
+        let mut data_node_ids = Vec::new();
+        for data_node in data_nodes {
+            data_node_ids.push(data_node.id());
+            self.id_to_data_nodes.insert(data_node.id(), data_node);
+        }
+        self.block_to_data_node_ids.insert(block_id.clone(), data_node_ids);
+        self.block_gen_stamps.insert(block_id, gen_stamp);
+        self.last_gen_stamp = self.last_gen_stamp.max(gen_stamp);
+        Ok(())
+    }
+
+    // allocate_gen_stamp hands out a generation stamp newer than any before it; it is not logged by itself,
+    // replaying the AddBlock that records the block's final stamp moves last_gen_stamp past it again
+    pub fn allocate_gen_stamp(&mut self) -> u64 {
+        self.last_gen_stamp += 1;
+        self.last_gen_stamp
+    }
+
+    // gen_stamp returns the generation stamp of the current version of a block, 0 for blocks written before stamps existed
+    pub fn gen_stamp(&self, block_id: &str) -> u64 {
+        self.block_gen_stamps.get(block_id).copied().unwrap_or(0)
+    }
+
+    // register_data_node adds the data node to the cluster under its "host:port" ID:
+    //     - a storage that comes back under a new address keeps its replicas, which move to the new ID
+    //     - a different storage showing up at a known address means the old disk and its replicas are gone
+    pub fn register_data_node(&mut self, data_node: SerializableNodeAddress, storage_id: &str, capacity: u64) {
+        let data_node_id = data_node.id();
+        let moved_from = self.data_node_storage_ids.iter()
+            .find(|(id, storage)| storage.as_str() == storage_id && **id != data_node_id)
+            .map(|(id, _)| id.clone());
+        if let Some(old_id) = moved_from {
+            for data_node_ids in self.block_to_data_node_ids.values_mut() {
+                for id in data_node_ids.iter_mut().filter(|id| **id == old_id) {
+                    *id = data_node_id.clone();
+                }
+            }
+            self.forget_data_node(&old_id);
+        }
+        if self.data_node_storage_ids.get(&data_node_id).is_some_and(|storage| storage != storage_id) {
+            for data_node_ids in self.block_to_data_node_ids.values_mut() {
+                data_node_ids.retain(|id| *id != data_node_id);
+            }
+        }
+        if !self.data_nodes.iter().any(|(addr, _)| addr.id() == data_node_id) {
+            self.data_nodes.push((data_node.clone(), 0));
+        }
+        self.id_to_data_nodes.insert(data_node_id.clone(), data_node);
+        self.data_node_storage_ids.insert(data_node_id.clone(), storage_id.to_string());
+        self.data_node_capacities.insert(data_node_id, capacity);
+    }
+
+    // forget_data_node drops the data node from the cluster membership maps
+    fn forget_data_node(&mut self, data_node_id: &str) {
+        self.data_nodes.retain(|(addr, _)| addr.id() != data_node_id);
+        self.id_to_data_nodes.remove(data_node_id);
+        self.data_node_storage_ids.remove(data_node_id);
+        self.data_node_capacities.remove(data_node_id);
+        self.data_node_stats.remove(data_node_id);
+        self.reported_orphans.remove(data_node_id);
+    }
+
+    // dead_data_nodes returns the data nodes that have not sent a heartbeat for more than timeout_ms before now,
+    // a data node that never sent one since the namenode started (last_seen = 0) is not considered dead
+    pub fn dead_data_nodes(&self, now: u64, timeout_ms: u64) -> Vec<SerializableNodeAddress> {
+        self.data_nodes.iter()
+            .filter(|(_, last_seen)| *last_seen != 0 && now.saturating_sub(*last_seen) > timeout_ms)
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    // reset_last_seen starts the heartbeat clock over after a restart: registered data nodes get until a full timeout
+    // after now to heartbeat again, the others are treated as never seen
+    pub fn reset_last_seen(&mut self, now: u64) {
+        for (addr, last_seen) in self.data_nodes.iter_mut() {
+            *last_seen = if self.data_node_storage_ids.contains_key(&addr.id()) { now } else { 0 };
+        }
+    }
+
+    // replication_work scans the block map for blocks whose live replicas are off repl_factor:
+    //     - under-replicated blocks are copied, the ones with the fewest replicas left first, skipping data nodes already busy with max_streams_per_node copies
+    //     - surplus replicas are deleted from the data nodes with the least space remaining
+    // blocks with no live replica left cannot be recovered and are skipped, as are blocks in_flight with an earlier copy
+    pub fn replication_work(&self, limits: &ReplicationLimits, in_flight: &HashSet<String>) -> ReplicationWork {
+        let repl_factor = self.repl_factor as usize;
+        let mut under_replicated = Vec::new();
+        let mut work = ReplicationWork::default();
+        for block_id in self.block_to_data_node_ids.keys().filter(|block_id| !self.blocks_under_construction.contains(*block_id)) {
+            let replicas = self.block_replicas(block_id);
+            if !replicas.is_empty() && replicas.len() < repl_factor {
+                if in_flight.contains(block_id) {
+                    continue;
+                }
+                under_replicated.push((replicas.len(), block_id.clone(), replicas));
+            } else if replicas.len() > repl_factor {
+                let mut holders: Vec<String> = replicas.iter().map(SerializableNodeAddress::id).collect();
+                holders.sort_by_key(|id| (self.data_node_stats.get(id).map_or(u64::MAX, |stats| stats.remaining), id.clone()));
+                for data_node_id in holders.into_iter().take(replicas.len() - repl_factor) {
+                    work.surplus.push((block_id.clone(), data_node_id));
+                }
+            }
+        }
+        work.surplus.sort();
+        work.surplus.truncate(limits.max_deletions);
+
+        under_replicated.sort_by(|(a_count, a_id, _), (b_count, b_id, _)| (a_count, a_id).cmp(&(b_count, b_id)));
+        let mut streams: HashMap<String, usize> = HashMap::new();
+        for (count, block_id, replicas) in under_replicated {
+            if work.copies.len() >= limits.max_replications {
+                break;
+            }
+            let busy = |id: &String| streams.get(id).copied().unwrap_or(0) >= limits.max_streams_per_node;
+            let sources: Vec<SerializableNodeAddress> = replicas.iter().filter(|replica| !busy(&replica.id())).cloned().collect();
+            let Some(source) = sources.first() else {
+                continue;
+            };
+            let mut excluded: Vec<String> = replicas.iter().map(SerializableNodeAddress::id).collect();
+            excluded.extend(self.data_nodes.iter().map(|(addr, _)| addr.id()).filter(busy));
+            let targets = self.choose_targets(repl_factor - count, &excluded);
+            if targets.is_empty() {
+                continue;
+            }
+            *streams.entry(source.id()).or_default() += 1;
+            for target in &targets {
+                *streams.entry(target.id()).or_default() += 1;
+            }
+            work.copies.push((block_id, sources, targets));
         }
+        work
+    }
+
+    // is_registered checks that the data node registered with this storage, reports from anything else are refused
+    pub fn is_registered(&self, data_node_id: &str, storage_id: &str) -> bool {
+        self.data_node_storage_ids.get(data_node_id).is_some_and(|registered| registered == storage_id)
+    }
+
+    // is_valid_replica checks that the block belongs to a file and the replica has the block's length and generation stamp
+    fn is_valid_replica(&self, block: &ReportedBlock) -> bool {
+        self.block_to_data_node_ids.contains_key(&block.block_id)
+            && self.block_lengths.get(&block.block_id).is_none_or(|expected| *expected == block.length)
+            && !self.is_stale_replica(block)
+    }
+
+    // is_stale_replica checks whether the replica is an older version of a block that belongs to a file
+    fn is_stale_replica(&self, block: &ReportedBlock) -> bool {
+        self.block_to_data_node_ids.contains_key(&block.block_id) && block.gen_stamp < self.gen_stamp(&block.block_id)
     }
+
+    fn holds_replica(&self, data_node_id: &str, block_id: &str) -> bool {
+        self.block_to_data_node_ids.get(block_id).is_some_and(|data_node_ids| data_node_ids.iter().any(|id| id == data_node_id))
+    }
+
+    // reconcile_block_report compares a full block report with the replicas the block map assigns to the data node
+    pub fn reconcile_block_report(&self, data_node_id: &str, reported: &[ReportedBlock]) -> BlockReportReconciliation {
+        let valid: HashSet<&str> = reported.iter()
+            .filter(|block| self.is_valid_replica(block))
+            .map(|block| block.block_id.as_str())
+            .collect();
+        let mut reconciliation = BlockReportReconciliation::default();
+        for (block_id, data_node_ids) in &self.block_to_data_node_ids {
+            if data_node_ids.iter().any(|id| id == data_node_id) && !valid.contains(block_id.as_str()) {
+                reconciliation.missing.push(block_id.clone());
+            }
+        }
+        for block in reported {
+            let block_id = &block.block_id;
+            if !self.block_to_data_node_ids.contains_key(block_id) {
+                reconciliation.orphaned.push(block_id.clone());
+            } else if self.is_stale_replica(block) {
+                reconciliation.stale.push(block_id.clone());
+            } else if !valid.contains(block_id.as_str()) {
+                reconciliation.wrong_length.push(block_id.clone());
+            } else if !self.holds_replica(data_node_id, block_id) {
+                reconciliation.extra.push(block_id.clone());
+            }
+        }
+        reconciliation.missing.sort();
+        reconciliation.orphaned.sort();
+        reconciliation.extra.sort();
+        reconciliation.stale.sort();
+        reconciliation.wrong_length.sort();
+        reconciliation
+    }
+
+    // confirm_orphans remembers the orphans of a data node's full block report and returns the ones its previous report had too,
+    // a block that is still being written belongs to its file by the next report so those are safe to delete
+    pub fn confirm_orphans(&mut self, data_node_id: &str, orphaned: &[String]) -> Vec<String> {
+        let previous = self.reported_orphans.insert(data_node_id.to_string(), orphaned.iter().cloned().collect()).unwrap_or_default();
+        orphaned.iter().filter(|block_id| previous.contains(*block_id)).cloned().collect()
+    }
+
+    // replica_ops turns replicas found on and gone from the data node into the edits recording them in the block map,
+    // received replicas are only recorded when they are valid and not recorded yet, gone ones only when they were recorded
+    pub fn replica_ops(&self, data_node_id: &str, received: &[ReportedBlock], gone: &[String]) -> Vec<EditOp> {
+        let mut ops = Vec::new();
+        if let Some(data_node) = self.id_to_data_nodes.get(data_node_id) {
+            for block in received {
+                if self.is_valid_replica(block) && !self.holds_replica(data_node_id, &block.block_id) {
+                    ops.push(EditOp::AddReplicas { block_id: block.block_id.clone(), data_nodes: vec![data_node.clone()] });
+                }
+            }
+        }
+        for block_id in gone {
+            if self.holds_replica(data_node_id, block_id) {
+                ops.push(EditOp::RemoveReplica { block_id: block_id.clone(), data_node_id: data_node_id.to_string() });
+            }
+        }
+        ops
+    }
+
+    // data_node_address returns the address of a data node by its ID
+    pub fn data_node_address(&self, data_node_id: &str) -> Option<SerializableNodeAddress> {
+        self.id_to_data_nodes.get(data_node_id).cloned()
+            .or_else(|| self.data_nodes.iter().find(|(addr, _)| addr.id() == data_node_id).map(|(addr, _)| addr.clone()))
+    }
+
+    // record_heartbeat marks the data node as seen at now with the usage it reported
+    pub fn record_heartbeat(&mut self, data_node_id: &str, stats: DataNodeStats, now: u64) {
+        for (addr, last_seen) in self.data_nodes.iter_mut() {
+            if addr.id() == data_node_id {
+                *last_seen = now;
+            }
+        }
+        self.data_node_stats.insert(data_node_id.to_string(), stats);
+    }
+
+    // remove_blocks forgets the blocks of a deleted or overwritten inode
+    fn remove_blocks(&mut self, block_ids: &[String]) {
+        for block_id in block_ids {
+            self.block_to_data_node_ids.remove(block_id);
+            self.block_lengths.remove(block_id);
+            self.block_gen_stamps.remove(block_id);
+            self.blocks_under_construction.remove(block_id);
+        }
+    }
+
+    // replicas_by_data_node groups the blocks by the data nodes holding their replicas
+    pub fn replicas_by_data_node(&self, block_ids: &[String]) -> HashMap<String, Vec<String>> {
+        let mut replicas: HashMap<String, Vec<String>> = HashMap::new();
+        for block_id in block_ids {
+            for data_node_id in self.block_to_data_node_ids.get(block_id).into_iter().flatten() {
+                replicas.entry(data_node_id.clone()).or_default().push(block_id.clone());
+            }
+        }
+        replicas
+    }
+
+    // blocks_in_range returns the ID, offset in the file and length of each block of the file overlapping length bytes from offset,
+    // length 0 meaning to the end of the file, along with the file's length; a file under construction cannot be read yet
+    pub fn blocks_in_range(&self, file_name: &str, offset: u64, length: u64) -> Result<(Vec<BlockExtent>, u64), Status> {
+        let file = self.namespace.get_file(file_name)?;
+        if file.under_construction {
+            return Err(Status::failed_precondition(format!("File {} is still being written", file_name)));
+        }
+        let blocks = &file.blocks;
+        let file_length = self.file_length(blocks);
+        if offset > file_length {
+            return Err(Status::out_of_range(format!("Offset {} is past the end of the file ({} bytes)", offset, file_length)));
+        }
+        let end = if length == 0 { file_length } else { offset.saturating_add(length).min(file_length) };
+        let mut in_range = Vec::new();
+        let mut block_offset = 0;
+        for block_id in blocks {
+            let block_length = self.block_lengths.get(block_id).copied().unwrap_or(0);
+            if block_offset >= end {
+                break;
+            }
+            if block_offset + block_length > offset {
+                in_range.push((block_id.clone(), block_offset, block_length));
+            }
+            block_offset += block_length;
+        }
+        Ok((in_range, file_length))
+    }
+
+    // file_length is the sum of the lengths of the blocks
+    pub fn file_length(&self, blocks: &[String]) -> u64 {
+        blocks.iter().map(|block_id| self.block_lengths.get(block_id).copied().unwrap_or(0)).sum()
+    }
+}
+
+// copy_block streams the block from the first replica that can be read in full and is not older than gen_stamp
+// into a write pipeline made of the targets
+async fn copy_block(block_id: &str, gen_stamp: u64, replicas: &[SerializableNodeAddress], targets: &[SerializableNodeAddress]) -> Result<(), String> {
+    let (first_node, nodes_left) = targets.split_first().ok_or_else(|| "no targets to copy to".to_string())?;
+    let mut last_error = format!("no replica of {} could be read", block_id);
+    for replica in replicas {
+        match pipe_block(block_id, gen_stamp, replica, first_node, nodes_left).await {
+            Ok(()) => return Ok(()),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// pipe_block streams a block from a replica into a write pipeline headed by first_node a packet at a time, verifying each packet
+// on the way; a replica that fails part way ends the write without its last packet, so the targets throw away what they got,
+// the copies are stored with gen_stamp
+async fn pipe_block(block_id: &str, gen_stamp: u64, replica: &SerializableNodeAddress, first_node: &SerializableNodeAddress, nodes_left: &[SerializableNodeAddress]) -> Result<(), String> {
+    let read_error = |e: &dyn std::fmt::Display| format!("failed to read {} from {}: {}", block_id, replica.id(), e);
+    let mut source = DataNodeClient::connect(format!("http://{}", replica.id())).await.map_err(|e| read_error(&e))?;
+    let request = Request::new(GetDataRequest { filename: block_id.to_string(), offset: 0, length: 0, gen_stamp });
+    let mut packets = source.get_data_stream(request).await.map_err(|e| read_error(&e.message()))?.into_inner();
+    let mut target = DataNodeClient::connect(format!("http://{}", first_node.id()))
+        .await
+        .map_err(|e| format!("failed to connect to {}: {}", first_node.id(), e))?;
+    let (sender, stream) = mpsc::channel(STREAM_BUFFER);
+    let write = tokio::spawn(async move { target.put_data_stream(ReceiverStream::new(stream)).await });
+    let piped = async {
+        let mut header = Some((block_id.to_string(), nodes_left.iter().map(SerializableNodeAddress::id).collect()));
+        while let Some(packet) = packets.message().await.map_err(|e| read_error(&e.message()))? {
+            checksum::verify(&packet.data, &packet.checksums).map_err(|chunk| read_error(&format!("checksum mismatch at chunk {}", chunk)))?;
+            let (block_id, nodes_left) = header.take().unwrap_or_default();
+            // the write failed, its result says why
+            if sender.send(PutDataPacket { block_id, nodes_left, data: packet.data, checksums: packet.checksums, last_packet: false, gen_stamp }).await.is_err() {
+                return Ok(());
+            }
+        }
+        let (block_id, nodes_left) = header.unwrap_or_default();
+        let _ = sender.send(PutDataPacket { block_id, nodes_left, last_packet: true, gen_stamp, ..Default::default() }).await;
+        Ok(())
+    };
+    if let Err(e) = piped.await {
+        write.abort();
+        return Err(e);
+    }
+    drop(sender);
+    let written = write.await
+        .map_err(|e| Status::internal(e.to_string()))
+        .and_then(|result| result.map(Response::into_inner));
+    let acks = pipeline_acks(&first_node.id(), written);
+    match acks.iter().find(|ack| !ack.success) {
+        Some(failed) => Err(format!("failed to put {} on {}: {}", block_id, failed.node, failed.message)),
+        None if acks.len() <= nodes_left.len() => Err(format!("pipeline for {} did not acknowledge the copy", block_id)),
+        None => Ok(()),
+    }
+}
+
+// put_block streams a block into a write pipeline made of the data nodes, the first one at its head, in packets carrying their checksums,
+// stamped with gen_stamp, and returns the acks of the data nodes the write reached in pipeline order
+async fn put_block(block_id: &str, gen_stamp: u64, data: &[u8], pipeline: &[SerializableNodeAddress]) -> Vec<PipelineAck> {
+    let Some((first_node, nodes_left)) = pipeline.split_first() else {
+        return Vec::new();
+    };
+    let written = async {
+        let mut client = DataNodeClient::connect(format!("http://{}", first_node.id()))
+            .await
+            .map_err(|e| Status::internal(format!("Failed to connect to DataNode: {}", e)))?;
+        let mut packets: Vec<PutDataPacket> = data.chunks(PACKET_SIZE)
+            .map(|packet| PutDataPacket { data: packet.to_vec(), checksums: checksum::chunk_checksums(packet), ..Default::default() })
+            .collect();
+        if packets.is_empty() {
+            packets.push(PutDataPacket::default());
+        }
+        packets[0].block_id = block_id.to_string();
+        packets[0].gen_stamp = gen_stamp;
+        packets[0].nodes_left = nodes_left.iter().map(SerializableNodeAddress::id).collect();
+        packets.last_mut().unwrap().last_packet = true;
+        Ok(client.put_data_stream(tokio_stream::iter(packets)).await?.into_inner())
+    };
+    pipeline_acks(&first_node.id(), written.await)
+}
+
+// fetch_from_replicas reads a range of a block from the first of its replicas that answers with data matching its checksums,
+// unavailable when the block has no replica and the last replica's error when none of them can be read
+async fn fetch_from_replicas(replicas: &[SerializableNodeAddress], block_id: &str, gen_stamp: u64, offset: u64, length: u64) -> Result<Vec<u8>, Status> {
+    let mut last_error = Status::unavailable(format!("No replicas of block {}", block_id));
+    for replica in replicas {
+        match fetch_range(&replica.id(), block_id, gen_stamp, offset, length).await {
+            Ok(range) => return Ok(range),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// deserialize_data_nodes reads the data node list, accepting the last_seen times saved now as well as the
+// unused bool flag older snapshots carried in their place, which is read as never seen
+fn deserialize_data_nodes<'de, D>(deserializer: D) -> Result<Vec<(SerializableNodeAddress, u64)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum LastSeen {
+        Millis(u64),
+        #[allow(dead_code)]
+        Flag(bool),
+    }
+    let data_nodes: Vec<(SerializableNodeAddress, LastSeen)> = Deserialize::deserialize(deserializer)?;
+    Ok(data_nodes.into_iter()
+        .map(|(addr, last_seen)| match last_seen {
+            LastSeen::Millis(millis) => (addr, millis),
+            LastSeen::Flag(_) => (addr, 0),
+        })
+        .collect())
 }
 
+// CommandQueue holds the commands waiting for each data node's next heartbeat and the ones sent but not acknowledged yet
 #[derive(Debug, Default)]
+pub struct CommandQueue {
+    next_command_id: u64,
+    // the pending commands of each data node, with the number of times each was sent before
+    pending: HashMap<String, Vec<(DataNodeCommand, u32)>>,
+    sent: HashMap<u64, SentCommand>,
+    // blocks the namenode is copying itself between data nodes, with when the copy started
+    copying: HashMap<String, u64>,
+}
+
+// SentCommand is a command handed out on a heartbeat response, waiting for the data node to acknowledge it
+#[derive(Debug)]
+struct SentCommand {
+    data_node_id: String,
+    command: DataNodeCommand,
+    sent_at: u64,
+    attempts: u32,
+}
+
+impl CommandQueue {
+    // queue adds a command for the data node's next heartbeat and returns its ID
+    pub fn queue(&mut self, data_node_id: &str, command: Command) -> u64 {
+        self.next_command_id += 1;
+        let command = DataNodeCommand { command_id: self.next_command_id, command: Some(command) };
+        self.pending.entry(data_node_id.to_string()).or_default().push((command, 0));
+        self.next_command_id
+    }
+
+    // queue_deletes adds a delete command for each of the data node's replicas, given as block ID and the generation stamp
+    // they were last known to have, so a newer replica the data node got in the meantime is kept
+    pub fn queue_deletes(&mut self, data_node_id: &str, replicas: Vec<(String, u64)>) {
+        for (block_id, gen_stamp) in replicas {
+            self.queue(data_node_id, Command::Delete(DeleteCommand { block_ids: vec![block_id], gen_stamp }));
+        }
+    }
+
+    // take hands out the data node's pending commands, they wait for an ack from then on;
+    // commands sent more than COMMAND_TIMEOUT_MS before now are given up on first, see retry
+    pub fn take(&mut self, data_node_id: &str, now: u64) -> Vec<DataNodeCommand> {
+        let expired: Vec<u64> = self.sent.iter()
+            .filter(|(_, sent)| sent.sent_at + COMMAND_TIMEOUT_MS <= now)
+            .map(|(command_id, _)| *command_id)
+            .collect();
+        for command_id in expired {
+            let sent_to = self.sent[&command_id].data_node_id.clone();
+            self.retry(&sent_to, command_id);
+        }
+        let commands = self.pending.remove(data_node_id).unwrap_or_default();
+        commands.into_iter()
+            .map(|(command, attempts)| {
+                let sent = SentCommand { data_node_id: data_node_id.to_string(), command: command.clone(), sent_at: now, attempts: attempts + 1 };
+                self.sent.insert(command.command_id, sent);
+                command
+            })
+            .collect()
+    }
+
+    // ack returns the command a data node acknowledged, None if it is unknown or was acknowledged before
+    pub fn ack(&mut self, data_node_id: &str, command_id: u64) -> Option<DataNodeCommand> {
+        match self.sent.get(&command_id) {
+            Some(sent) if sent.data_node_id == data_node_id => self.sent.remove(&command_id).map(|sent| sent.command),
+            _ => None,
+        }
+    }
+
+    // retry gives up on a command the data node failed or never acknowledged: a delete command is queued again
+    // until it was sent MAX_COMMAND_ATTEMPTS times, any other command is dropped, a replicate command's block
+    // is then no longer in flight and the replication monitor schedules it again on its next pass
+    pub fn retry(&mut self, data_node_id: &str, command_id: u64) {
+        let Some(sent) = self.sent.remove(&command_id) else {
+            return;
+        };
+        if sent.data_node_id != data_node_id {
+            self.sent.insert(command_id, sent);
+            return;
+        }
+        if matches!(sent.command.command, Some(Command::Delete(_))) && sent.attempts < MAX_COMMAND_ATTEMPTS {
+            self.pending.entry(sent.data_node_id).or_default().push((sent.command, sent.attempts));
+        }
+    }
+
+    // forget drops every command for a data node that is gone or registered again
+    pub fn forget(&mut self, data_node_id: &str) {
+        self.pending.remove(data_node_id);
+        self.sent.retain(|_, sent| sent.data_node_id != data_node_id);
+    }
+
+    // start_copy records a copy of the block the namenode makes itself, so the block is in flight until finish_copy
+    pub fn start_copy(&mut self, block_id: &str, now: u64) {
+        self.copying.insert(block_id.to_string(), now);
+    }
+
+    pub fn finish_copy(&mut self, block_id: &str) {
+        self.copying.remove(block_id);
+    }
+
+    // blocks_in_flight returns the blocks with a replicate command pending, or sent less than COMMAND_TIMEOUT_MS before now
+    // and not acknowledged yet, and the blocks the namenode started copying itself less than COMMAND_TIMEOUT_MS before now;
+    // they are not scheduled again
+    pub fn blocks_in_flight(&self, now: u64) -> HashSet<String> {
+        self.pending.values().flatten().map(|(command, _)| command)
+            .chain(self.sent.values().filter(|sent| sent.sent_at + COMMAND_TIMEOUT_MS > now).map(|sent| &sent.command))
+            .filter_map(|command| match &command.command {
+                Some(Command::Replicate(replicate)) => Some(replicate.block_id.clone()),
+                _ => None,
+            })
+            .chain(self.copying.iter().filter(|(_, started)| *started + COMMAND_TIMEOUT_MS > now).map(|(block_id, _)| block_id.clone()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct NameNodeService {
     pub state: Arc<RwLock<NameNodeState>>,
+    // where checkpoints of the state are saved, None keeps the state in memory only
+    pub state_file: Option<PathBuf>,
+    // write-ahead log of the edits made since the last checkpoint
+    pub edit_log: Option<Arc<Mutex<EditLog>>>,
+    // commands for the data nodes, handed out on heartbeat responses
+    pub commands: Arc<Mutex<CommandQueue>>,
+}
+
+impl NameNodeService {
+    // log_and_apply appends the ops to the edit log and then applies them to the state,
+    // called with the write lock held so edits are logged in the order they are applied;
+    // the append and its fsync run on the blocking pool so they do not stall the runtime thread
+    async fn log_and_apply(&self, state: &mut NameNodeState, ops: Vec<EditOp>) -> Result<(), Status> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let timestamp = now_millis();
+        let mut entries: Vec<EditLogEntry> = ops.into_iter()
+            .enumerate()
+            .map(|(i, op)| EditLogEntry { txid: state.last_txid + 1 + i as u64, timestamp, op })
+            .collect();
+        if let Some(edit_log) = self.edit_log.clone() {
+            let appended = tokio::task::spawn_blocking(move || {
+                let appended = edit_log.lock().unwrap().append(&entries);
+                (appended, entries)
+            });
+            let (appended, logged) = appended.await.map_err(|e| Status::internal(format!("Failed to write edit log: {}", e)))?;
+            appended.map_err(|e| Status::internal(format!("Failed to write edit log: {}", e)))?;
+            entries = logged;
+        }
+        // the entries are durable now, so their txids are taken whatever happens next; an entry that fails to apply is
+        // skipped and the rest applied, just as replay would after a restart, and the first failure is returned
+        state.last_txid = entries.last().map_or(state.last_txid, |entry| entry.txid);
+        let mut failed = None;
+        for entry in entries {
+            if let Err(e) = state.apply(&entry.op, entry.timestamp) {
+                failed.get_or_insert(e);
+            }
+        }
+        failed.map_or(Ok(()), |e| Err(e.into()))
+    }
+
+    // checkpoint folds the edit log into a new state snapshot and truncates the log,
+    // the write lock keeps edits out while the snapshot is taken; the save and truncate run on the blocking pool
+    // with the lock moved in, so they do not stall the runtime thread
+    pub async fn checkpoint(&self) -> io::Result<()> {
+        let state = self.state.clone().write_owned().await;
+        let (state_file, edit_log) = (self.state_file.clone(), self.edit_log.clone());
+        let checkpointed = tokio::task::spawn_blocking(move || {
+            if let Some(state_file) = state_file {
+                state.save(&state_file)?;
+            }
+            if let Some(edit_log) = edit_log {
+                edit_log.lock().unwrap().truncate()?;
+            }
+            Ok(())
+        });
+        checkpointed.await.map_err(io::Error::other)?
+    }
+
+    // invalidate_blocks asks each data node to delete its replicas of blocks that no longer belong to any file, whatever their version:
+    // registered data nodes get a delete command on their next heartbeat, which is sent again until they acknowledge it or
+    // MAX_COMMAND_ATTEMPTS is reached, the others are dialed directly and keep the blocks on disk if they cannot be reached
+    async fn invalidate_blocks(&self, mut replicas: HashMap<String, Vec<String>>) {
+        {
+            let state = self.state.read().await;
+            let mut commands = self.commands.lock().unwrap();
+            replicas.retain(|data_node_id, block_ids| {
+                if !state.data_node_storage_ids.contains_key(data_node_id) {
+                    return true;
+                }
+                commands.queue(data_node_id, Command::Delete(DeleteCommand { block_ids: std::mem::take(block_ids), gen_stamp: u64::MAX }));
+                false
+            });
+        }
+        let deletions = replicas.into_iter().map(|(data_node_id, block_ids)| async move {
+            let mut client = DataNodeClient::connect(format!("http://{}", data_node_id)).await?;
+            client.delete_blocks(Request::new(DeleteBlocksRequest { block_ids })).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        });
+        for result in futures::future::join_all(deletions).await {
+            if let Err(e) = result {
+                println!("Failed to invalidate blocks: {}", e);
+            }
+        }
+    }
+
+    // touch_file records a read of file_name by bumping its access time, unless the last one is within ACCESS_TIME_PRECISION_MS;
+    // that is checked under the read lock first, so reads only queue up behind the write lock when there is an edit to log
+    async fn touch_file(&self, file_name: &str) -> Result<(), Status> {
+        let file_name = normalize_path(file_name)?;
+        let atime = now_millis();
+        let is_recent = |state: &NameNodeState| -> Result<bool, Status> {
+            Ok(state.namespace.get_file(&file_name)?.atime + ACCESS_TIME_PRECISION_MS > atime)
+        };
+        if is_recent(&*self.state.read().await)? {
+            return Ok(());
+        }
+        let mut state = self.state.write().await;
+        // another read may have bumped it in between
+        if is_recent(&state)? {
+            return Ok(());
+        }
+        self.log_and_apply(&mut state, vec![EditOp::SetAccessTime { file_name: file_name.clone(), atime }]).await
+    }
+
+    // write_block Exhaustive Explanation:
+    //     1. Stream the block through a write pipeline of the data nodes, stamped with a new generation stamp
+    //     2. Keep the data nodes that acknowledged the block and leave the ones that failed out for good
+    //     3. Rebuild the pipeline from the data nodes that acknowledged the block, the ones the failure kept it from and a replacement for each failed one,
+    //        chosen among the data nodes that neither hold the block nor failed, and push the block through it again under a newer generation stamp,
+    //        so whatever a failed data node kept of the block is stale should it come back
+    //     4. Stop once a pipeline goes through without a failure or no data node is left to try, the replication monitor makes up for missing replicas
+    //     5. Return the data nodes holding the block and its generation stamp, failing if there are none
+    async fn write_block(&self, block_id: &str, data: &[u8], data_nodes: Vec<SerializableNodeAddress>) -> Result<(Vec<SerializableNodeAddress>, u64), Status> {
+        let mut stored = Vec::new();
+        let mut failed: Vec<String> = Vec::new();
+        let mut pipeline = data_nodes;
+        let mut gen_stamp = 0;
+        while !pipeline.is_empty() {
+            gen_stamp = self.state.write().await.allocate_gen_stamp();
+            let acks = put_block(block_id, gen_stamp, data, &pipeline).await;
+            let mut unreached = Vec::new();
+            let mut newly_failed = 0;
+            for data_node in pipeline {
+                match acks.iter().find(|ack| ack.node == data_node.id()) {
+                    Some(ack) if ack.success => stored.push(data_node),
+                    Some(ack) => {
+                        println!("Write pipeline for {} lost {}: {}", block_id, ack.node, ack.message);
+                        failed.push(data_node.id());
+                        newly_failed += 1;
+                    }
+                    None => unreached.push(data_node),
+                }
+            }
+            if newly_failed == 0 {
+                break;
+            }
+            let mut excluded: Vec<String> = stored.iter().chain(&unreached).map(SerializableNodeAddress::id).collect();
+            excluded.extend(failed.iter().cloned());
+            let replacements = self.state.read().await.choose_targets(newly_failed, &excluded);
+            pipeline = std::mem::take(&mut stored).into_iter().chain(unreached).chain(replacements).collect();
+        }
+        if stored.is_empty() {
+            return Err(Status::unavailable(format!("No data node could store block {}", block_id)));
+        }
+        Ok((stored, gen_stamp))
+    }
+
+    // phoenix removes a dead data node from the cluster and re-replicates the blocks that fell below repl_factor with it,
+    // it backs both the Phoenixing RPC and the dead node monitor; its copies are in flight for the replication monitor,
+    // which leaves their blocks alone, and blocks the monitor already has in flight are left to it
+    pub async fn phoenix(&self, data_node: NodeAddress) -> Result<PhoenixingResult, Status> {
+        let data_node_uri = format!("{}:{}", data_node.host, data_node.port);
+
+        // Use a write lock to modify the state
+        let mut state = self.state.write().await;
+        let repl_factor = state.repl_factor as usize;
+        let in_flight = self.commands.lock().unwrap().blocks_in_flight(now_millis());
+        let mut under_replicated_blocks: Vec<String> = state.block_to_data_node_ids.iter()
+            .filter(|(block, data_node_ids)| data_node_ids.contains(&data_node_uri) && data_node_ids.len() <= repl_factor && !in_flight.contains(*block))
+            .map(|(block, _)| block.clone())
+            .collect();
+        under_replicated_blocks.sort();
+        self.log_and_apply(&mut state, vec![EditOp::RemoveDataNode { data_node_id: data_node_uri.clone() }]).await?;
+        self.commands.lock().unwrap().forget(&data_node_uri);
+        // plan every copy while the lock is held, the data itself moves without it
+        let copies: Vec<(String, u64, Vec<SerializableNodeAddress>, Vec<SerializableNodeAddress>)> = under_replicated_blocks.into_iter()
+            .map(|block_id| {
+                let replicas = state.block_replicas(&block_id);
+                let holders: Vec<String> = replicas.iter().map(SerializableNodeAddress::id).collect();
+                let targets = state.choose_targets(repl_factor.saturating_sub(replicas.len()), &holders);
+                let gen_stamp = state.gen_stamp(&block_id);
+                (block_id, gen_stamp, replicas, targets)
+            })
+            .collect();
+        {
+            let mut commands = self.commands.lock().unwrap();
+            for (block_id, _, _, _) in &copies {
+                commands.start_copy(block_id, now_millis());
+            }
+        }
+        drop(state);
+
+        let mut outcomes = Vec::new();
+        let mut new_nodes = Vec::new();
+        for (block_id, gen_stamp, replicas, targets) in copies {
+            let copied = if replicas.is_empty() {
+                Err("no replica is left to copy from".to_string())
+            } else if targets.is_empty() {
+                Err("no data node is available to hold a new replica".to_string())
+            } else {
+                copy_block(&block_id, gen_stamp, &replicas, &targets).await
+            };
+            // the copy is no longer in flight once its replicas are recorded, the lock keeps the monitor from seeing it in between
+            let mut state = self.state.write().await;
+            self.commands.lock().unwrap().finish_copy(&block_id);
+            let outcome = match copied {
+                Ok(()) => {
+                    self.log_and_apply(&mut state, vec![EditOp::AddReplicas { block_id: block_id.clone(), data_nodes: targets.clone() }]).await?;
+                    let target_nodes: Vec<NodeAddress> = targets.into_iter().map(NodeAddress::from).collect();
+                    new_nodes.extend(target_nodes.iter().cloned());
+                    BlockReplicationOutcome { block_id, success: true, message: "Re-replicated".to_string(), new_nodes: target_nodes }
+                }
+                Err(message) => BlockReplicationOutcome { block_id, success: false, message, new_nodes: vec![] },
+            };
+            outcomes.push(outcome);
+        }
+        let replicated = outcomes.iter().filter(|outcome| outcome.success).count();
+        let message = format!("Re-replicated {} of {} under-replicated blocks of {}", replicated, outcomes.len(), data_node_uri);
+        Ok(PhoenixingResult { success: replicated == outcomes.len(), message, new_nodes, outcomes })
+    }
+
+    // replicate_and_trim runs one pass of the replication monitor, copying under-replicated blocks to new targets and
+    // deleting surplus replicas within the limits, and returns how many copies and deletions were made or handed out:
+    // registered data nodes get the work as commands on their next heartbeat, the others are dialed directly
+    pub async fn replicate_and_trim(&self, limits: &ReplicationLimits) -> Result<(usize, usize), Status> {
+        let mut state = self.state.write().await;
+        let in_flight = self.commands.lock().unwrap().blocks_in_flight(now_millis());
+        let mut work = state.replication_work(limits, &in_flight);
+        // forget the surplus replicas before deleting them, so no reader is sent to a replica that is going away
+        let ops = work.surplus.iter()
+            .map(|(block_id, data_node_id)| EditOp::RemoveReplica { block_id: block_id.clone(), data_node_id: data_node_id.clone() })
+            .collect();
+        self.log_and_apply(&mut state, ops).await?;
+
+        let deleted = work.surplus.len();
+        let mut replicas: HashMap<String, Vec<String>> = HashMap::new();
+        for (block_id, data_node_id) in work.surplus {
+            replicas.entry(data_node_id).or_default().push(block_id);
+        }
+        let mut commanded = 0;
+        {
+            let mut commands = self.commands.lock().unwrap();
+            replicas.retain(|data_node_id, block_ids| {
+                if !state.data_node_storage_ids.contains_key(data_node_id) {
+                    return true;
+                }
+                let replicas = std::mem::take(block_ids).into_iter().map(|block_id| (block_id.clone(), state.gen_stamp(&block_id))).collect();
+                commands.queue_deletes(data_node_id, replicas);
+                false
+            });
+            work.copies.retain(|(block_id, sources, targets)| {
+                let Some(source) = sources.iter().find(|source| state.data_node_storage_ids.contains_key(&source.id())) else {
+                    return true;
+                };
+                let targets = targets.iter().map(SerializableNodeAddress::id).collect();
+                let gen_stamp = state.gen_stamp(block_id);
+                commands.queue(&source.id(), Command::Replicate(ReplicateCommand { block_id: block_id.clone(), targets, gen_stamp }));
+                commanded += 1;
+                false
+            });
+        }
+        let gen_stamps: HashMap<String, u64> = work.copies.iter().map(|(block_id, _, _)| (block_id.clone(), state.gen_stamp(block_id))).collect();
+        {
+            let mut commands = self.commands.lock().unwrap();
+            for (block_id, _, _) in &work.copies {
+                commands.start_copy(block_id, now_millis());
+            }
+        }
+        drop(state);
+        self.invalidate_blocks(replicas).await;
+
+        let copies = work.copies.into_iter().map(|(block_id, sources, targets)| {
+            let gen_stamp = gen_stamps[&block_id];
+            async move {
+                let copied = copy_block(&block_id, gen_stamp, &sources, &targets).await;
+                (block_id, targets, copied)
+            }
+        });
+        let mut replicated = commanded;
+        for (block_id, targets, copied) in futures::future::join_all(copies).await {
+            let mut state = self.state.write().await;
+            self.commands.lock().unwrap().finish_copy(&block_id);
+            match copied {
+                Ok(()) => {
+                    self.log_and_apply(&mut state, vec![EditOp::AddReplicas { block_id, data_nodes: targets }]).await?;
+                    replicated += 1;
+                }
+                Err(e) => println!("Failed to re-replicate {}: {}", block_id, e),
+            }
+        }
+        Ok((replicated, deleted))
+    }
+
+    // abandon_expired_files abandons the files under construction that got no new block for FILE_LEASE_MS before now,
+    // their client went away without completing or abandoning them, and returns how many there were
+    pub async fn abandon_expired_files(&self, now: u64) -> Result<usize, Status> {
+        let state = self.state.write().await;
+        let expired: Vec<String> = state.namespace.files_under_construction().into_iter()
+            .filter(|(_, mtime)| mtime + FILE_LEASE_MS <= now)
+            .map(|(file_name, _)| file_name)
+            .collect();
+        let count = expired.len();
+        if count > 0 {
+            self.abandon_files(state, expired).await?;
+        }
+        Ok(count)
+    }
+
+    // abandon_files deletes files under construction and their blocks, telling the data nodes to delete the replicas reported so far
+    async fn abandon_files(&self, mut state: RwLockWriteGuard<'_, NameNodeState>, file_names: Vec<String>) -> Result<(), Status> {
+        let block_ids: Vec<String> = file_names.iter()
+            .filter_map(|file_name| state.namespace.get_file(file_name).ok())
+            .flat_map(|file| file.blocks.clone())
+            .collect();
+        let replicas = state.replicas_by_data_node(&block_ids);
+        let ops = file_names.into_iter().map(|path| EditOp::Delete { path, recursive: false }).collect();
+        self.log_and_apply(&mut state, ops).await?;
+        drop(state);
+        self.invalidate_blocks(replicas).await;
+        Ok(())
+    }
+
+    // dead_data_nodes returns the data nodes whose last heartbeat is more than timeout_ms old
+    pub async fn dead_data_nodes(&self, timeout_ms: u64) -> Vec<SerializableNodeAddress> {
+        self.state.read().await.dead_data_nodes(now_millis(), timeout_ms)
+    }
+
+    // has_pending_edits reports whether the edit log has grown since the last checkpoint
+    pub fn has_pending_edits(&self) -> bool {
+        self.edit_log.as_ref().is_some_and(|edit_log| !edit_log.lock().unwrap().is_empty())
+    }
 }
 
 #[tonic::async_trait]
@@ -87,60 +1193,170 @@ impl NameNode for NameNodeService {
     }
     // read_file Exhaustive Explanation:
     //     1. Get the request from the client
-    //     2. Get the file blocks from the FileNameToBlocks map
-    //     3. For each block, get the block addresses from the BlockToDataNodeIds map
-    //     4. For each block address, get the DataNodeInstance from the IdToDataNodes map
-    //     5. Append the DataNodeInstance to the block addresses slice
-    //     6. Append the block addresses slice to the reply
+    //     2. Under the read lock, collect every block of the file with its replicas from the BlockToDataNodeIds map and its generation stamp, then drop the lock
+    //     3. For each block, try each replica in order until one answers with data matching its checksums
+    //     4. Fail the read if a block has no replica that can be read, rather than returning the file with a hole in it
+    //     5. Update the file's access time and return the blocks' data in file order
     async fn read_file(&self, request: Request<ReadFileRequest>) -> Result<Response<ReadFileResponse>, Status> {
+        let req = request.into_inner();
+        let blocks: Vec<(String, Vec<SerializableNodeAddress>, u64)> = {
+            let state = self.state.read().await;
+            let (blocks, _) = state.blocks_in_range(&req.filename, 0, 0)?;
+            blocks.into_iter()
+                .map(|(block_id, _, _)| {
+                    let replicas = state.block_replicas(&block_id);
+                    let gen_stamp = state.gen_stamp(&block_id);
+                    (block_id, replicas, gen_stamp)
+                })
+                .collect()
+        };
+        let mut file_data = Vec::new();
+        for (block_id, replicas, gen_stamp) in blocks {
+            file_data.extend_from_slice(&fetch_from_replicas(&replicas, &block_id, gen_stamp, 0, 0).await?);
+        }
+        self.touch_file(&req.filename).await?;
+        let response = ReadFileResponse { data: file_data };
+        Ok(Response::new(response))
+    }
+
+    // get_block_locations Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Get the file blocks from the Namespace
+    //     3. Walk the blocks in order, tracking each block's offset in the file from the BlockLengths map
+    //     4. Skip the blocks that end before the requested offset and stop after the requested range (length = 0 means to the end of the file)
+    //     5. For each block in range, get its data node addresses from the BlockToDataNodeIds and IdToDataNodes maps
+    //     6. Append the block and its addresses to the reply, the client reads the data from the data nodes directly
+    async fn get_block_locations(&self, request: Request<GetBlockLocationsRequest>) -> Result<Response<GetBlockLocationsResponse>, Status> {
         let req = request.into_inner();
         let state = self.state.read().await;
-        let file_blocks = state.file_name_to_blocks.get(&req.filename);
-        if let Some(blocks) = file_blocks {
-            let mut file_data = Vec::new();
-            for block_id in blocks {
-                if let Some(data_node_ids) = state.block_to_data_node_ids.get(block_id) {
-                    if let Some(data_node_id) = data_node_ids.first() {
-                        if let Some(data_node) = state.id_to_data_nodes.get(data_node_id) {
-                            let mut client = DataNodeClient::connect(format!("http://{}:{}", data_node.host, data_node.port))
-                                .await
-                                .map_err(|e| Status::internal(format!("Failed to connect to DataNode: {}", e)))?;
-                            
-                            let get_data_request = Request::new(GetDataRequest {
-                                filename: block_id.clone(),
-                            });
-                            
-                            let response = client.get_data(get_data_request)
-                                .await
-                                .map_err(|e| Status::internal(format!("Failed to get data from DataNode: {}", e)))?;
-                            
-                            file_data.extend_from_slice(&response.into_inner().data);
-                        }
-                    }
-                }
-            }
-            let response = ReadFileResponse { data: file_data };
-            Ok(Response::new(response))
-        } else {
-            Err(Status::not_found("File not found"))
+        let (blocks, file_length) = state.blocks_in_range(&req.filename, req.offset, req.length)?;
+        let located_blocks = blocks.into_iter()
+            .map(|(block_id, offset, length)| {
+                let nodes = state.block_replicas(&block_id).into_iter().map(NodeAddress::from).collect();
+                let gen_stamp = state.gen_stamp(&block_id);
+                LocatedBlock { block_id, nodes, offset, length, gen_stamp }
+            })
+            .collect();
+        drop(state);
+        self.touch_file(&req.filename).await?;
+        let response = GetBlockLocationsResponse { blocks: located_blocks, file_length };
+        Ok(Response::new(response))
+    }
+
+    // pread Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Map the range to the blocks of the file overlapping it
+    //     3. For each block, work out the part of the range it holds and read only that part, trying each replica in order until one answers with data matching its checksums
+    //     4. Update the file's access time and return the bytes in file order
+    async fn pread(&self, request: Request<PreadRequest>) -> Result<Response<PreadResponse>, Status> {
+        let req = request.into_inner();
+        let (blocks, file_length) = self.state.read().await.blocks_in_range(&req.filename, req.offset, req.length)?;
+        let end = if req.length == 0 { file_length } else { req.offset.saturating_add(req.length).min(file_length) };
+        let mut data = Vec::new();
+        for (block_id, block_offset, block_length) in blocks {
+            let start = req.offset.max(block_offset) - block_offset;
+            let length = end.min(block_offset + block_length) - block_offset - start;
+            let (replicas, gen_stamp) = {
+                let state = self.state.read().await;
+                (state.block_replicas(&block_id), state.gen_stamp(&block_id))
+            };
+            data.extend_from_slice(&fetch_from_replicas(&replicas, &block_id, gen_stamp, start, length).await?);
         }
+        self.touch_file(&req.filename).await?;
+        Ok(Response::new(PreadResponse { data }))
+    }
+
+    // get_file_info Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Get the inode at the path from the Namespace
+    //     3. For a file, sum its block lengths from the BlockLengths map and report its replication, block size and block count
+    //     4. For a directory, report only its modification time
+    //     5. Append the info to the reply
+    async fn get_file_info(&self, request: Request<GetFileInfoRequest>) -> Result<Response<GetFileInfoResponse>, Status> {
+        let req = request.into_inner();
+        let path = normalize_path(&req.path)?;
+        let state = self.state.read().await;
+        let info = match state.namespace.get(&path)? {
+            INode::File(file) => FileInfo {
+                path,
+                is_directory: false,
+                length: state.file_length(&file.blocks),
+                block_size: file.block_size,
+                replication: file.replication,
+                block_count: file.blocks.len() as u64,
+                mtime: file.mtime,
+                atime: file.atime,
+                under_construction: file.under_construction,
+            },
+            INode::Directory(dir) => FileInfo {
+                path,
+                is_directory: true,
+                mtime: dir.mtime,
+                ..Default::default()
+            },
+        };
+        Ok(Response::new(GetFileInfoResponse { info: Some(info) }))
     }
 
     // write_file Exhaustive Explanation:
     //     1. Get the request from the client
-    //     2. Get the file name from the request
-    //     3. Get the file size from the request
-    //     4. Calculate the number of blocks to allocate
-    //     5. Assign the blocks using assign_blocks_for_file
-    //     6. Append the blocks to the reply
+    //     2. Normalize the path and reject the write if the file already exists or its parent is a file, or if the block size is 0
+    //     3. Split the data into block_size chunks
+    //     4. For each chunk, generate a new block ID and choose the replica targets
+    //     5. Stream the chunk in packets to the first target with the remaining targets as nodes_left, rebuilding the pipeline around data nodes that fail
+    //     6. Once every block is stored, log and apply the edits creating the file in the Namespace and recording the blocks in the BlockToDataNodeIds and IdToDataNodes maps
+    //     7. If a block could not be stored or the file could not be recorded, invalidate the blocks already stored before returning the error
     async fn write_file(&self, request: Request<WriteFileRequest>) -> Result<Response<WriteFileResponse>, Status> {
         let req = request.into_inner();
-        let file_name = req.filename;
-        let file_size = req.data.len() as u32;
-        let state = self.state.read().await;
-        let num_blocks = (file_size + state.block_size - 1) / state.block_size;
-        let request = Request::new(AssignBlocksForFileRequest { filename: file_name.clone() });
-        let blocks = self.assign_blocks_for_file(request).await?.into_inner().nodes;
+        let file_name = normalize_path(&req.filename)?;
+        let block_size = {
+            let state = self.state.read().await;
+            state.namespace.check_create(&file_name)?;
+            state.block_size as usize
+        };
+        if block_size == 0 {
+            return Err(Status::invalid_argument("Block size must be greater than 0"));
+        }
+        let mut blocks = Vec::new();
+        let written = async {
+            for chunk in req.data.chunks(block_size) {
+                let block_id = String::from(BlockId::generate());
+                let data_nodes = self.state.read().await.choose_data_nodes(None);
+                if data_nodes.is_empty() {
+                    return Err(Status::unavailable("No data nodes available"));
+                }
+                let (data_nodes, gen_stamp) = self.write_block(&block_id, chunk, data_nodes).await?;
+                blocks.push((block_id, chunk.len() as u64, data_nodes, gen_stamp));
+            }
+            Ok(())
+        };
+        let written = written.await;
+        let recorded = async {
+            written?;
+            let mut state = self.state.write().await;
+            state.namespace.check_create(&file_name)?;
+            let mut ops = vec![EditOp::CreateFile { file_name: file_name.clone(), replication: state.repl_factor, block_size: state.block_size, under_construction: false }];
+            for (block_id, length, data_nodes, gen_stamp) in &blocks {
+                ops.push(EditOp::AddBlock {
+                    file_name: file_name.clone(),
+                    block_id: block_id.clone(),
+                    length: *length,
+                    data_nodes: data_nodes.clone(),
+                    gen_stamp: *gen_stamp,
+                });
+            }
+            self.log_and_apply(&mut state, ops).await
+        };
+        if let Err(e) = recorded.await {
+            let mut replicas: HashMap<String, Vec<String>> = HashMap::new();
+            for (block_id, _, data_nodes, _) in blocks {
+                for data_node in data_nodes {
+                    replicas.entry(data_node.id()).or_default().push(block_id.clone());
+                }
+            }
+            self.invalidate_blocks(replicas).await;
+            return Err(e);
+        }
         let response = WriteFileResponse { success: true };
         Ok(Response::new(response))
     }
@@ -152,68 +1368,367 @@ impl NameNode for NameNodeService {
     //     5. Initialize the dead data node ID
     //     6. Iterate over the IdToDataNodes map to find the dead data node
     //     7. Delete the dead data node from the IdToDataNodes map
-    //     8. Construct the under-replicated blocks list and de-register the block entirely in favour of re-creation
-    //     9. Verify if re-replication would be possible
-    //     10. Iterate over the IdToDataNodes map to get the available nodes
-    //     11. Attempt re-replication of under-replicated blocks
+    //     8. Construct the under-replicated blocks list and drop the dead data node from the BlockToDataNodeIds map
+    //     9. Verify if re-replication would be possible: a surviving replica to copy from and a data node that does not hold the block yet,
+    //        leaving out the blocks the replication monitor already has in flight
+    //     10. Iterate over the DataNodes list to choose the new targets
+    //     11. Attempt re-replication of under-replicated blocks, each in flight for the replication monitor until its outcome is recorded
     //     12. Fetch the data from the healthy data node
-    //     13. Initiate the replication of the block contents
-    //     14. Append the block addresses to the block addresses slice
-    //     15. Append the block addresses slice to the reply
+    //     13. Initiate the replication of the block contents through a write pipeline of the targets
+    //     14. Log and apply the edit adding the targets to the BlockToDataNodeIds map
+    //     15. Append the per-block outcome and the new block addresses to the reply
     async fn phoenixing(&self, request: Request<NodeAddress>) -> Result<Response<PhoenixingResult>, Status> {
         let req = request.into_inner();
-        let data_node_uri = format!("{}:{}", req.host, req.port);
-        let mut under_replicated_blocks = Vec::new();
-        let mut new_nodes = Vec::new();
+        let response = self.phoenix(req).await?;
+        Ok(Response::new(response))
+    }
 
-        // Use a write lock to modify the state
+    // mkdirs Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Normalize the path
+    //     3. Reject the request if any component of the path is a file
+    //     4. Log and apply the edit creating the directory and its missing parents
+    async fn mkdirs(&self, request: Request<MkdirsRequest>) -> Result<Response<MkdirsResponse>, Status> {
+        let req = request.into_inner();
+        let path = normalize_path(&req.path)?;
         let mut state = self.state.write().await;
+        state.namespace.check_mkdirs(&path)?;
+        self.log_and_apply(&mut state, vec![EditOp::Mkdirs { path }]).await?;
+        Ok(Response::new(MkdirsResponse { success: true }))
+    }
 
-        for (block, data_node_ids) in state.block_to_data_node_ids.iter() {
-            if data_node_ids.contains(&data_node_uri) {
-                let mut data_node_ids = data_node_ids.clone();
-                data_node_ids.retain(|id| id != &data_node_uri);
-                if data_node_ids.len() < state.repl_factor as usize {
-                    under_replicated_blocks.push(block.clone());
+    // list_directory Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Get the children of the directory from the Namespace, or the file itself if the path is a file
+    //     3. For each file, sum its block lengths from the BlockLengths map
+    //     4. Append an entry per child to the reply
+    async fn list_directory(&self, request: Request<ListDirectoryRequest>) -> Result<Response<ListDirectoryResponse>, Status> {
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let entries = state.namespace.list(&req.path)?
+            .into_iter()
+            .map(|(name, inode)| DirectoryEntry {
+                name,
+                is_directory: inode.is_directory(),
+                length: match inode {
+                    INode::File(file) => state.file_length(&file.blocks),
+                    INode::Directory(_) => 0,
+                },
+            })
+            .collect();
+        Ok(Response::new(ListDirectoryResponse { entries }))
+    }
+
+    // delete Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Normalize the path
+    //     3. Reject the request for the root, a missing path, or a non-empty directory without recursive
+    //     4. Collect the data nodes holding replicas of every block in the subtree
+    //     5. Log and apply the edit removing the subtree from the Namespace and its blocks from the BlockToDataNodeIds map
+    //     6. Tell the data nodes to delete the replicas so the disk space is reclaimed
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let req = request.into_inner();
+        let path = normalize_path(&req.path)?;
+        let mut state = self.state.write().await;
+        let block_ids = state.namespace.check_delete(&path, req.recursive)?.block_ids();
+        let replicas = state.replicas_by_data_node(&block_ids);
+        self.log_and_apply(&mut state, vec![EditOp::Delete { path, recursive: req.recursive }]).await?;
+        drop(state);
+        self.invalidate_blocks(replicas).await;
+        Ok(Response::new(DeleteResponse { success: true }))
+    }
+
+    // delete_file Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Normalize the path and reject it unless it is an existing file
+    //     3. Collect the data nodes holding replicas of the file's blocks
+    //     4. Log and apply the edit removing the file from the Namespace and its blocks from the BlockToDataNodeIds map
+    //     5. Tell the data nodes to delete the replicas so the disk space is reclaimed
+    async fn delete_file(&self, request: Request<DeleteFileRequest>) -> Result<Response<DeleteFileResponse>, Status> {
+        let req = request.into_inner();
+        let file_name = normalize_path(&req.filename)?;
+        let mut state = self.state.write().await;
+        let block_ids = state.namespace.get_file(&file_name)?.blocks.clone();
+        let replicas = state.replicas_by_data_node(&block_ids);
+        self.log_and_apply(&mut state, vec![EditOp::Delete { path: file_name, recursive: false }]).await?;
+        drop(state);
+        self.invalidate_blocks(replicas).await;
+        Ok(Response::new(DeleteFileResponse { success: true }))
+    }
+
+    // rename Exhaustive Explanation:
+    //     1. Get the request from the client
+    //     2. Normalize the source and destination paths
+    //     3. Reject a missing source, an existing destination without overwrite, and moving a directory into its own subtree
+    //     4. Collect the data nodes holding replicas of the destination's blocks if it is overwritten
+    //     5. Log and apply the edit moving the inode in the Namespace, the blocks themselves are not touched
+    //     6. Tell the data nodes to delete the replicas of the overwritten destination
+    async fn rename(&self, request: Request<RenameRequest>) -> Result<Response<RenameResponse>, Status> {
+        let req = request.into_inner();
+        let src = normalize_path(&req.src)?;
+        let dst = normalize_path(&req.dst)?;
+        let mut state = self.state.write().await;
+        let replaced_block_ids = state.namespace.check_rename(&src, &dst, req.overwrite)?
+            .map(INode::block_ids)
+            .unwrap_or_default();
+        let replicas = state.replicas_by_data_node(&replaced_block_ids);
+        self.log_and_apply(&mut state, vec![EditOp::Rename { src, dst, overwrite: req.overwrite }]).await?;
+        drop(state);
+        self.invalidate_blocks(replicas).await;
+        Ok(Response::new(RenameResponse { success: true }))
+    }
+
+    // register_data_node Exhaustive Explanation:
+    //     1. Get the request from the data node
+    //     2. Reject the request if the storage ID, host or port is missing
+    //     3. Build the data node ID from the host and port
+    //     4. Log and apply the edit adding the data node to the DataNodes list and the IdToDataNodes map, moving the replicas of a storage that changed address
+    //     5. Drop the commands queued for or sent to the data node before, it will not acknowledge them after a restart
+    //     6. Reply with the data node ID
+    async fn register_data_node(&self, request: Request<RegisterDataNodeRequest>) -> Result<Response<RegisterDataNodeResponse>, Status> {
+        let req = request.into_inner();
+        if req.storage_id.is_empty() || req.host.is_empty() || req.port == 0 {
+            return Err(Status::invalid_argument("Registration needs a storage ID, host and port"));
+        }
+        let data_node = SerializableNodeAddress { host: req.host, port: req.port };
+        let datanode_id = data_node.id();
+        let mut state = self.state.write().await;
+        self.log_and_apply(&mut state, vec![EditOp::RegisterDataNode { data_node, storage_id: req.storage_id, capacity: req.capacity }]).await?;
+        self.commands.lock().unwrap().forget(&datanode_id);
+        println!("Registered data node {}", datanode_id);
+        Ok(Response::new(RegisterDataNodeResponse { datanode_id }))
+    }
+
+    // heartbeat Exhaustive Explanation:
+    //     1. Get the request from the data node
+    //     2. Ask the data node to register again if its ID is unknown or it reports a different storage than it registered, e.g. after a namenode restart lost it
+    //     3. Record the time of the heartbeat in the DataNodes list and the reported usage in the DataNodeStats map
+    //     4. Heartbeats are not logged, they only matter while the data node stays alive
+    //     5. For each acknowledged command, log and apply the edit recording the new replicas of a successful replicate command,
+    //        a failed command is retried or dropped, see CommandQueue::retry
+    //     6. Reply with the commands queued for the data node since its last heartbeat
+    async fn heartbeat(&self, request: Request<HeartbeatRequest>) -> Result<Response<HeartbeatResponse>, Status> {
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if !state.is_registered(&req.datanode_id, &req.storage_id) {
+            return Ok(Response::new(HeartbeatResponse { reregister: true, commands: vec![] }));
+        }
+        let stats = DataNodeStats {
+            capacity: req.capacity,
+            used: req.used,
+            remaining: req.remaining,
+            active_transfers: req.active_transfers,
+            failed_volumes: req.failed_volumes,
+        };
+        state.record_heartbeat(&req.datanode_id, stats, now_millis());
+        let mut ops = Vec::new();
+        let commands = {
+            let mut queue = self.commands.lock().unwrap();
+            for ack in req.acks {
+                if !ack.success {
+                    println!("Data node {} failed command {}: {}", req.datanode_id, ack.command_id, ack.message);
+                    queue.retry(&req.datanode_id, ack.command_id);
+                    continue;
+                }
+                if let Some(Command::Replicate(replicate)) = queue.ack(&req.datanode_id, ack.command_id).and_then(|command| command.command) {
+                    let data_nodes = replicate.targets.iter().filter_map(|id| state.data_node_address(id)).collect();
+                    ops.push(EditOp::AddReplicas { block_id: replicate.block_id, data_nodes });
                 }
             }
+            queue.take(&req.datanode_id, now_millis())
+        };
+        self.log_and_apply(&mut state, ops).await?;
+        Ok(Response::new(HeartbeatResponse { reregister: false, commands }))
+    }
+
+    // block_report Exhaustive Explanation:
+    //     1. Get the request from the data node, refusing it if any block ID is not well formed
+    //     2. Ask the data node to register again if it is not registered with the storage it reports
+    //     3. Compare the reported blocks, lengths and generation stamps with the data node's replicas in the BlockToDataNodeIds map
+    //     4. Log and apply the edits forgetting the missing replicas, stale ones among them, and recording the extra ones
+    //     5. Queue a delete command for the stale and wrong-length replicas at the stamp they were reported with, neither is ever worth keeping
+    //     6. Queue a delete command for the orphaned replicas the previous full report had too, an orphan seen once is only flagged
+    //        because a block being written shows up here before its file records it
+    //     7. Reply with the missing, orphaned, extra and stale block IDs
+    async fn block_report(&self, request: Request<BlockReportRequest>) -> Result<Response<BlockReportResponse>, Status> {
+        let req = request.into_inner();
+        for block in &req.blocks {
+            BlockId::parse(&block.block_id)?;
         }
-        state.id_to_data_nodes.remove(&data_node_uri);
-        for block in under_replicated_blocks {
-            if let Some(data_node_ids) = state.block_to_data_node_ids.get(&block) {
-                for data_node_id in data_node_ids {
-                    if let Some(data_node) = state.id_to_data_nodes.get(data_node_id) {
-                        new_nodes.push(NodeAddress::from((*data_node).clone()));
-                    }
-                }
+        let mut state = self.state.write().await;
+        if !state.is_registered(&req.datanode_id, &req.storage_id) {
+            return Ok(Response::new(BlockReportResponse { reregister: true, ..Default::default() }));
+        }
+        let reconciliation = state.reconcile_block_report(&req.datanode_id, &req.blocks);
+        let confirmed_orphans = state.confirm_orphans(&req.datanode_id, &reconciliation.orphaned);
+        let unwanted = req.blocks.iter()
+            .filter(|block| reconciliation.stale.contains(&block.block_id) || reconciliation.wrong_length.contains(&block.block_id) || confirmed_orphans.contains(&block.block_id))
+            .map(|block| (block.block_id.clone(), block.gen_stamp))
+            .collect();
+        let extra: Vec<ReportedBlock> = req.blocks.into_iter().filter(|block| reconciliation.extra.contains(&block.block_id)).collect();
+        let ops = state.replica_ops(&req.datanode_id, &extra, &reconciliation.missing);
+        self.log_and_apply(&mut state, ops).await?;
+        self.commands.lock().unwrap().queue_deletes(&req.datanode_id, unwanted);
+        if reconciliation != BlockReportReconciliation::default() {
+            println!("Block report from {}: {} missing, {} orphaned ({} deleted), {} extra, {} stale, {} wrong-length replicas",
+                req.datanode_id, reconciliation.missing.len(), reconciliation.orphaned.len(), confirmed_orphans.len(), reconciliation.extra.len(),
+                reconciliation.stale.len(), reconciliation.wrong_length.len());
+        }
+        Ok(Response::new(BlockReportResponse {
+            reregister: false,
+            missing_block_ids: reconciliation.missing,
+            orphaned_block_ids: reconciliation.orphaned,
+            extra_block_ids: reconciliation.extra,
+            stale_block_ids: reconciliation.stale,
+        }))
+    }
+
+    // incremental_block_report Exhaustive Explanation:
+    //     1. Get the request from the data node, refusing it if any block ID is not well formed
+    //     2. Ask the data node to register again if it is not registered with the storage it reports
+    //     3. Log and apply the edits recording the received replicas of known blocks and forgetting the deleted ones
+    //     4. Queue a delete command for received replicas older than their block's generation stamp, at the stamp they were reported with
+    async fn incremental_block_report(&self, request: Request<IncrementalBlockReportRequest>) -> Result<Response<IncrementalBlockReportResponse>, Status> {
+        let req = request.into_inner();
+        for block in &req.received {
+            BlockId::parse(&block.block_id)?;
+        }
+        BlockId::parse_all(&req.deleted_block_ids)?;
+        let mut state = self.state.write().await;
+        if !state.is_registered(&req.datanode_id, &req.storage_id) {
+            return Ok(Response::new(IncrementalBlockReportResponse { reregister: true }));
+        }
+        let ops = state.replica_ops(&req.datanode_id, &req.received, &req.deleted_block_ids);
+        self.log_and_apply(&mut state, ops).await?;
+        let stale = req.received.iter()
+            .filter(|block| state.is_stale_replica(block))
+            .map(|block| (block.block_id.clone(), block.gen_stamp))
+            .collect();
+        self.commands.lock().unwrap().queue_deletes(&req.datanode_id, stale);
+        Ok(Response::new(IncrementalBlockReportResponse { reregister: false }))
+    }
+
+    // report_bad_blocks Exhaustive Explanation:
+    //     1. Get the request from the data node, refusing it if any block ID is not well formed
+    //     2. Ask the data node to register again if it is not registered with the storage it reports
+    //     3. Log and apply the edits forgetting the corrupt replicas the data node holds, except a block's last replica which is better kept than lost
+    //     4. Queue a delete command for the forgotten replicas, the replication monitor copies their blocks back up to the replication factor
+    async fn report_bad_blocks(&self, request: Request<ReportBadBlocksRequest>) -> Result<Response<ReportBadBlocksResponse>, Status> {
+        let req = request.into_inner();
+        BlockId::parse_all(&req.block_ids)?;
+        let mut state = self.state.write().await;
+        if !state.is_registered(&req.datanode_id, &req.storage_id) {
+            return Ok(Response::new(ReportBadBlocksResponse { reregister: true }));
+        }
+        let mut corrupt = Vec::new();
+        for block_id in req.block_ids {
+            if !state.holds_replica(&req.datanode_id, &block_id) {
+                continue;
+            }
+            if state.block_replicas(&block_id).len() > 1 {
+                corrupt.push(block_id);
+            } else {
+                println!("Only replica of {} on {} is corrupt, keeping it", block_id, req.datanode_id);
             }
         }
-        let response = PhoenixingResult { success: true, message: "Phoenixing completed".to_string(), new_nodes };
-        Ok(Response::new(response))
+        let ops = corrupt.iter()
+            .map(|block_id| EditOp::RemoveReplica { block_id: block_id.clone(), data_node_id: req.datanode_id.clone() })
+            .collect();
+        self.log_and_apply(&mut state, ops).await?;
+        let corrupt = corrupt.into_iter().map(|block_id| (block_id.clone(), state.gen_stamp(&block_id))).collect();
+        self.commands.lock().unwrap().queue_deletes(&req.datanode_id, corrupt);
+        Ok(Response::new(ReportBadBlocksResponse { reregister: false }))
     }
 
     // assign_blocks_for_file Exhaustive Explanation:
-    //     1. Initialize the FileNameToBlocks map with an empty slice for the given file name
-    //     2. Create a list of available data node IDs
-    //     3. Determine the number of data nodes available
+    //     1. Normalize the path and reject the allocation if the file already exists or its parent is a file, or if the block size is 0
+    //     2. Calculate the number of blocks needed for the file size
+    //     3. Start the edits with the creation of the file, under construction until the client completes or abandons it unless it has no blocks
     //     4. Iterate through the number of blocks to allocate
-    //     5. Generate a new block ID
-    //     6. Append the block ID to the FileNameToBlocks map using the file name as the key
-    //     7. Calculate the replication factor based on the number of data nodes available
-    //     8. Assign data nodes to the block using the assign_data_nodes function
-    //     9. Append the block addresses to the block addresses slice
-    //     10. Append the block addresses slice to the reply
+    //     5. Generate a new block ID and generation stamp
+    //     6. Ask the placement policy for the data nodes of the block, the first one heads the write pipeline and is on the client's host if it can be
+    //     7. Add an edit recording the block in the Namespace map without replicas or length, as nothing is written yet;
+    //        replicas are added as the data nodes report them or when the client completes the file, the length when it completes the file
+    //     8. Log and apply the edits, then append the blocks and their node addresses to the reply
     async fn assign_blocks_for_file(&self, request: Request<AssignBlocksForFileRequest>) -> Result<Response<AssignBlocksForFileResponse>, Status> {
         let req = request.into_inner();
-        let file_name = req.filename;
+        let file_name = normalize_path(&req.filename)?;
         let mut blocks = Vec::new();
-        let mut state = self.state.write().await; // Change to write lock
-        for _ in 0..state.repl_factor {
-            let block_id = format!("block_{}", Uuid::new_v4());
-            state.file_name_to_blocks.entry(file_name.clone()).or_insert(Vec::new()).push(block_id.clone());
-            blocks.push(block_id);
+        let mut state = self.state.write().await;
+        state.namespace.check_create(&file_name)?;
+        if state.block_size == 0 {
+            return Err(Status::invalid_argument("Block size must be greater than 0"));
+        }
+        let num_blocks = req.file_size.div_ceil(state.block_size as u64);
+        if num_blocks > 0 && state.data_nodes.is_empty() {
+            return Err(Status::unavailable("No data nodes available"));
+        }
+        let mut ops = vec![EditOp::CreateFile { file_name: file_name.clone(), replication: state.repl_factor, block_size: state.block_size, under_construction: num_blocks > 0 }];
+        for index in 0..num_blocks {
+            let block_id = String::from(BlockId::generate());
+            let offset = index * state.block_size as u64;
+            let length = (req.file_size - offset).min(state.block_size as u64);
+            let data_nodes = state.choose_data_nodes(Some(req.client_host.as_str()).filter(|host| !host.is_empty()));
+            let gen_stamp = state.allocate_gen_stamp();
+            blocks.push(LocatedBlock {
+                block_id: block_id.clone(),
+                nodes: data_nodes.iter().cloned().map(NodeAddress::from).collect(),
+                offset,
+                length,
+                gen_stamp,
+            });
+            ops.push(EditOp::AddBlock { file_name: file_name.clone(), block_id, length: 0, data_nodes: vec![], gen_stamp });
         }
-        let response = AssignBlocksForFileResponse { nodes: blocks };
+        self.log_and_apply(&mut state, ops).await?;
+        let response = AssignBlocksForFileResponse { blocks };
         Ok(Response::new(response))
     }
+
+    // complete_file Exhaustive Explanation:
+    //     1. Normalize the path and refuse the request unless the file is under construction
+    //     2. Refuse it unless the blocks are the ones assigned to the file, in order, none longer than the file's block size
+    //     3. Add an edit recording, for each block, the registered data nodes that acknowledged it as its replicas
+    //     4. Log and apply the edits together with the one recording the lengths of the blocks, which completes the file
+    async fn complete_file(&self, request: Request<CompleteFileRequest>) -> Result<Response<CompleteFileResponse>, Status> {
+        let req = request.into_inner();
+        let file_name = normalize_path(&req.filename)?;
+        let mut state = self.state.write().await;
+        let file = state.namespace.get_file(&file_name)?;
+        if !file.under_construction {
+            return Err(Status::failed_precondition(format!("File {} is not under construction", file_name)));
+        }
+        if !req.blocks.iter().map(|block| &block.block_id).eq(file.blocks.iter()) {
+            return Err(Status::invalid_argument(format!("Blocks do not match the ones assigned to {}", file_name)));
+        }
+        if let Some(block) = req.blocks.iter().find(|block| block.length > file.block_size as u64) {
+            return Err(Status::invalid_argument(format!("Block {} is longer than the block size {}", block.block_id, file.block_size)));
+        }
+        let mut ops = Vec::new();
+        for block in &req.blocks {
+            let data_nodes: Vec<SerializableNodeAddress> = block.nodes.iter()
+                .filter_map(|node| state.data_node_address(&SerializableNodeAddress::from(node.clone()).id()))
+                .collect();
+            if !data_nodes.is_empty() {
+                ops.push(EditOp::AddReplicas { block_id: block.block_id.clone(), data_nodes });
+            }
+        }
+        let block_lengths = req.blocks.into_iter().map(|block| (block.block_id, block.length)).collect();
+        ops.push(EditOp::CompleteFile { file_name, block_lengths });
+        self.log_and_apply(&mut state, ops).await?;
+        Ok(Response::new(CompleteFileResponse { success: true }))
+    }
+
+    // abandon_file Exhaustive Explanation:
+    //     1. Normalize the path and refuse the request unless the file is under construction
+    //     2. Log and apply the edit deleting the file and its blocks, then tell the data nodes to delete the replicas reported so far
+    async fn abandon_file(&self, request: Request<AbandonFileRequest>) -> Result<Response<AbandonFileResponse>, Status> {
+        let req = request.into_inner();
+        let file_name = normalize_path(&req.filename)?;
+        let state = self.state.write().await;
+        if !state.namespace.get_file(&file_name)?.under_construction {
+            return Err(Status::failed_precondition(format!("File {} is not under construction", file_name)));
+        }
+        self.abandon_files(state, vec![file_name]).await?;
+        Ok(Response::new(AbandonFileResponse { success: true }))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use rand::seq::SliceRandom;
+use crate::nnlib::{NameNodeState, SerializableNodeAddress};
+
+// BlockPlacementPolicy decides which data nodes hold the replicas of a block, for new blocks and for copies alike
+pub trait BlockPlacementPolicy: Send + Sync + fmt::Debug {
+    // choose_targets returns up to count distinct data nodes in pipeline order, none of them one of the excluded data node IDs;
+    // writer is the host the block is written from when it is known
+    fn choose_targets(&self, cluster: &NameNodeState, writer: Option<&str>, count: usize, excluded: &[String]) -> Vec<SerializableNodeAddress>;
+}
+
+// Random picks data nodes uniformly at random, whatever room they have left
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl BlockPlacementPolicy for Random {
+    fn choose_targets(&self, cluster: &NameNodeState, _writer: Option<&str>, count: usize, excluded: &[String]) -> Vec<SerializableNodeAddress> {
+        let candidates: Vec<&SerializableNodeAddress> = candidates(cluster, excluded).collect();
+        candidates
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .map(|addr| (*addr).clone())
+            .collect()
+    }
+}
+
+// RandomWithCapacity picks data nodes at random among those with room left for a whole block,
+// a data node on the writer's host heads the pipeline so the first replica is written locally
+#[derive(Debug, Default)]
+pub struct RandomWithCapacity;
+
+impl BlockPlacementPolicy for RandomWithCapacity {
+    fn choose_targets(&self, cluster: &NameNodeState, writer: Option<&str>, count: usize, excluded: &[String]) -> Vec<SerializableNodeAddress> {
+        let mut candidates: Vec<&SerializableNodeAddress> = candidates(cluster, excluded)
+            .filter(|addr| remaining(cluster, &addr.id()).is_none_or(|remaining| remaining >= cluster.block_size as u64))
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        if let Some(local) = writer.and_then(|writer| candidates.iter().position(|addr| addr.host == writer)) {
+            candidates.swap(0, local);
+        }
+        candidates.into_iter().take(count).cloned().collect()
+    }
+}
+
+// candidates are the data nodes of the cluster that are not excluded
+fn candidates<'a>(cluster: &'a NameNodeState, excluded: &'a [String]) -> impl Iterator<Item = &'a SerializableNodeAddress> {
+    cluster.data_nodes.iter()
+        .map(|(addr, _)| addr)
+        .filter(|addr| !excluded.contains(&addr.id()))
+}
+
+// remaining is the room a data node has left as of its last heartbeat, or its capacity if it registered but has not heartbeated yet,
+// nothing is known about data nodes from the static list
+fn remaining(cluster: &NameNodeState, data_node_id: &str) -> Option<u64> {
+    cluster.data_node_stats.get(data_node_id).map(|stats| stats.remaining)
+        .or_else(|| cluster.data_node_capacities.get(data_node_id).copied())
+}
+
+// PlacementPolicy is the policy the namenode places replicas with, chosen at startup by name
+#[derive(Debug, Clone)]
+pub struct PlacementPolicy(pub Arc<dyn BlockPlacementPolicy>);
+
+impl Default for PlacementPolicy {
+    fn default() -> Self {
+        PlacementPolicy(Arc::new(RandomWithCapacity))
+    }
+}
+
+impl FromStr for PlacementPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "random-with-capacity" => Ok(PlacementPolicy(Arc::new(RandomWithCapacity))),
+            "random" => Ok(PlacementPolicy(Arc::new(Random))),
+            _ => Err(format!("unknown placement policy {}, expected random-with-capacity or random", name)),
+        }
+    }
+}
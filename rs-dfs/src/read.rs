@@ -0,0 +1,33 @@
+// reads of a block straight from a data node, shared by the namenode and the client
+use tonic::{Request, Status};
+use crate::checksum;
+use crate::datanode::data_node_client::DataNodeClient;
+use crate::datanode::GetDataRequest;
+
+// fetch_range reads length bytes of a block from offset from the data node at address (host:port) over GetDataStream,
+// length 0 reading to the end of the block, refusing a replica older than gen_stamp; every packet is verified against
+// its checksums before the whole chunks it carries are cut down to the range
+pub async fn fetch_range(address: &str, block_id: &str, gen_stamp: u64, offset: u64, length: u64) -> Result<Vec<u8>, Status> {
+    let mut client = DataNodeClient::connect(format!("http://{}", address))
+        .await
+        .map_err(|e| Status::internal(format!("Failed to connect to DataNode: {}", e)))?;
+    let request = Request::new(GetDataRequest { filename: block_id.to_string(), offset, length, gen_stamp });
+    let mut packets = client.get_data_stream(request)
+        .await
+        .map_err(|e| Status::internal(format!("Failed to get data from DataNode: {}", e)))?
+        .into_inner();
+    let mut data = Vec::new();
+    let mut start = None;
+    while let Some(packet) = packets.message().await.map_err(|e| Status::internal(format!("Failed to get data from DataNode: {}", e)))? {
+        checksum::verify(&packet.data, &packet.checksums)
+            .map_err(|chunk| Status::data_loss(format!("Block {} failed checksum verification at chunk {}", block_id, chunk)))?;
+        start.get_or_insert(packet.offset);
+        data.extend_from_slice(&packet.data);
+    }
+    let skip = (offset.saturating_sub(start.unwrap_or(offset)) as usize).min(data.len());
+    data.drain(..skip);
+    if length > 0 {
+        data.truncate(length as usize);
+    }
+    Ok(data)
+}
@@ -1,61 +1,351 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
-use tonic::{Request, Response, Status};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tonic::{Code, Request};
+use rs_dfs::{checksum, datanode, namenode, BlockId};
+#[allow(dead_code)]
+#[path = "../src/prj/datanode/dnlib.rs"]
+mod dnlib;
+#[allow(dead_code)]
+#[path = "../src/prj/datanode/storage.rs"]
+mod storage;
+#[allow(dead_code)]
+#[path = "../src/prj/datanode/scanner.rs"]
+mod scanner;
 use datanode::data_node_server::DataNode;
-use datanode::{PulseRequest, PulseResponse, GetDataRequest, GetDataResponse, PutDataRequest, PutDataResponse, ReplicationPassthroughRequest, ReplicationPassthroughResponse};
-pub struct DataNodeService {
-    data_dir: String,
+use datanode::{DeleteBlocksRequest, GetDataRequest, PulseRequest, PutDataRequest};
+use dnlib::DataNodeService;
+use scanner::BlockScanner;
+
+fn temp_data_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rs-dfs-dn-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// block_file is where a data node keeps a finalized block
+fn block_file(data_dir: &Path, block_id: &str) -> PathBuf {
+    storage::block_path(data_dir, &BlockId::parse(block_id).unwrap())
+}
+
+fn meta_file(data_dir: &Path, block_id: &str) -> PathBuf {
+    storage::meta_path(data_dir, &BlockId::parse(block_id).unwrap())
+}
+
+#[tokio::test]
+async fn pulse_reports_success() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let response = datanode.pulse(Request::new(PulseRequest { pulse: true, host: None, port: None })).await.unwrap();
+    assert!(response.into_inner().success);
+    fs::remove_dir_all(data_dir).unwrap();
 }
 
-impl DataNodeService {
-    pub fn new(data_dir: String) -> Self {
-        DataNodeService { data_dir }
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_pulses_do_not_trip_over_each_others_probes() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let pulses: Vec<_> = (0..200).map(|_| {
+        let datanode = datanode.clone();
+        tokio::spawn(async move { datanode.pulse(Request::new(PulseRequest { pulse: true, host: None, port: None })).await.unwrap().into_inner().success })
+    }).collect();
+    for pulse in pulses {
+        assert!(pulse.await.unwrap());
     }
+    assert_eq!(fs::read_dir(&data_dir).unwrap().count(), 0);
+    fs::remove_dir_all(data_dir).unwrap();
 }
 
-#[tonic::async_trait]
-impl DataNode for DataNodeService {
-    async fn pulse(&self, request: Request<PulseRequest>) -> Result<Response<PulseResponse>, Status> {
-        let req = request.into_inner();
-        let success = if req.pulse {
-            // Handle heartbeat
-            true
-        } else {
-            // Handle initial ping
-            true
-        };
-        Ok(Response::new(PulseResponse { success }))
+#[tokio::test]
+async fn put_data_then_get_data_returns_block() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    let put = PutDataRequest { block_id: block_id.clone(), data: b"hello world".to_vec(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+    assert!(datanode.put_data(Request::new(put)).await.unwrap().into_inner().success);
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 };
+    let response = datanode.get_data(Request::new(get)).await.unwrap();
+    assert_eq!(response.into_inner().data, b"hello world");
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn put_data_overwrites_longer_block() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    let put = PutDataRequest { block_id: block_id.clone(), data: b"a much longer payload".to_vec(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+    datanode.put_data(Request::new(put)).await.unwrap();
+    let put = PutDataRequest { block_id: block_id.clone(), data: b"short".to_vec(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+    datanode.put_data(Request::new(put)).await.unwrap();
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 };
+    let response = datanode.get_data(Request::new(get)).await.unwrap();
+    assert_eq!(response.into_inner().data, b"short");
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn pulse_fails_when_the_data_dir_is_unusable() {
+    let data_dir = temp_data_dir().join("missing");
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let response = datanode.pulse(Request::new(PulseRequest { pulse: true, host: None, port: None })).await.unwrap();
+    assert!(!response.into_inner().success);
+    fs::remove_dir_all(data_dir.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn get_data_returns_checksums_and_refuses_a_corrupt_block() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    let data = vec![7u8; checksum::BYTES_PER_CHECKSUM * 2 + 10];
+    let put = PutDataRequest { block_id: block_id.clone(), data: data.clone(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+    datanode.put_data(Request::new(put)).await.unwrap();
+    assert!(meta_file(&data_dir, &block_id).exists());
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 };
+    let response = datanode.get_data(Request::new(get.clone())).await.unwrap().into_inner();
+    assert_eq!(response.checksums, checksum::chunk_checksums(&data));
+    assert_eq!(response.bytes_per_checksum as usize, checksum::BYTES_PER_CHECKSUM);
+
+    // flip a byte in the second chunk behind the data node's back
+    let mut corrupt = data.clone();
+    corrupt[checksum::BYTES_PER_CHECKSUM + 1] ^= 0xff;
+    fs::write(block_file(&data_dir, &block_id), corrupt).unwrap();
+    let status = datanode.get_data(Request::new(get)).await.unwrap_err();
+    assert_eq!(status.code(), Code::DataLoss);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn put_data_rejects_data_that_does_not_match_its_checksums() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    let put = PutDataRequest { block_id: block_id.clone(), data: b"hello world".to_vec(), nodes_left: vec![], checksums: checksum::chunk_checksums(b"hello there"), gen_stamp: 0 };
+    let status = datanode.put_data(Request::new(put)).await.unwrap_err();
+    assert_eq!(status.code(), Code::DataLoss);
+    assert!(!block_file(&data_dir, &block_id).exists());
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn blocks_written_before_checksums_existed_are_still_readable() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    // written to the top of the data directory, before blocks were kept in the finalized directory
+    fs::write(data_dir.join(&block_id), b"legacy block").unwrap();
+    assert_eq!(storage::recover(&data_dir).unwrap().migrated, 1);
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 };
+    let response = datanode.get_data(Request::new(get)).await.unwrap().into_inner();
+    assert_eq!(response.data, b"legacy block");
+    assert_eq!(response.checksums, checksum::chunk_checksums(b"legacy block"));
+    assert!(meta_file(&data_dir, &block_id).exists());
+    // the meta file was written aside and renamed into place, nothing is left behind in the in-progress directory
+    assert_eq!(fs::read_dir(data_dir.join("rbw")).unwrap().count(), 0);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn block_scanner_finds_corrupt_blocks_in_throttled_batches_and_resumes_after_a_restart() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let mut block_ids: Vec<String> = (0..3).map(|_| BlockId::generate().to_string()).collect();
+    block_ids.sort();
+    for block_id in &block_ids {
+        let put = PutDataRequest { block_id: block_id.clone(), data: vec![1u8; 100], nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+        datanode.put_data(Request::new(put)).await.unwrap();
     }
+    fs::write(block_file(&data_dir, &block_ids[2]), vec![2u8; 100]).unwrap();
 
-    async fn get_data(&self, request: Request<GetDataRequest>) -> Result<Response<GetDataResponse>, Status> {
-        let req = request.into_inner();
-        let file_path = Path::new(&self.data_dir).join(req.filename);
-        let mut file = File::open(file_path).map_err(|e| Status::internal(format!("Failed to open file: {}", e)))?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).map_err(|e| Status::internal(format!("Failed to read file: {}", e)))?;
-        Ok(Response::new(GetDataResponse { data }))
+    // 150 bytes a batch covers one 100 byte block at a time, the restarted scanner carries on with the second block
+    let mut scanner = BlockScanner::new(data_dir.clone(), 150, 1000);
+    assert!(scanner.pass_due(0));
+    assert!(scanner.scan_batch(150, 10).unwrap().is_empty());
+    let mut scanner = BlockScanner::new(data_dir.clone(), 150, 1000);
+    assert!(scanner.pass_due(20));
+    assert!(scanner.scan_batch(150, 20).unwrap().is_empty());
+    assert_eq!(scanner.scan_batch(150, 30).unwrap(), vec![block_ids[2].clone()]);
+
+    // the next pass waits for the scan period
+    let scanner = BlockScanner::new(data_dir.clone(), 150, 1000);
+    assert!(!scanner.pass_due(500));
+    assert!(scanner.pass_due(1030));
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn get_data_reads_the_chunks_holding_a_range() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    let data: Vec<u8> = (0..2000).map(|i| (i % 256) as u8).collect();
+    let put = PutDataRequest { block_id: block_id.clone(), data: data.clone(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+    datanode.put_data(Request::new(put)).await.unwrap();
+
+    // bytes 600..1100 live in the second and third 512 byte chunks
+    let get = GetDataRequest { filename: block_id.clone(), offset: 600, length: 500, gen_stamp: 0 };
+    let response = datanode.get_data(Request::new(get)).await.unwrap().into_inner();
+    assert_eq!(response.offset, 512);
+    assert_eq!(response.data, data[512..1536]);
+    assert_eq!(response.checksums, checksum::chunk_checksums(&data[512..1536]));
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 1900, length: 0, gen_stamp: 0 };
+    let response = datanode.get_data(Request::new(get)).await.unwrap().into_inner();
+    assert_eq!((response.offset, response.data), (1536, data[1536..].to_vec()));
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 2001, length: 1, gen_stamp: 0 };
+    assert_eq!(datanode.get_data(Request::new(get)).await.unwrap_err().code(), Code::OutOfRange);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn block_ids_that_are_not_well_formed_are_refused() {
+    let data_dir = temp_data_dir().join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let outside = data_dir.parent().unwrap().join("outside");
+    fs::write(&outside, b"not a block").unwrap();
+
+    let traversals = ["../outside".to_string(), outside.to_string_lossy().to_string(), "block_../../outside".to_string(), String::new()];
+    for block_id in traversals {
+        let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 };
+        assert_eq!(datanode.get_data(Request::new(get)).await.unwrap_err().code(), Code::InvalidArgument);
+        let put = PutDataRequest { block_id: block_id.clone(), data: b"overwritten".to_vec(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+        assert_eq!(datanode.put_data(Request::new(put)).await.unwrap_err().code(), Code::InvalidArgument);
+        let delete = DeleteBlocksRequest { block_ids: vec![block_id] };
+        assert_eq!(datanode.delete_blocks(Request::new(delete)).await.unwrap_err().code(), Code::InvalidArgument);
     }
+    assert_eq!(fs::read(&outside).unwrap(), b"not a block");
+    assert_eq!(fs::read_dir(&data_dir).unwrap().count(), 0);
+    fs::remove_dir_all(data_dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn block_ids_parse_only_when_well_formed() {
+    let block_id = BlockId::generate();
+    assert_eq!(BlockId::parse(block_id.as_str()), Ok(block_id.clone()));
+    assert!(BlockId::parse(&block_id.as_str().to_uppercase()).is_err());
+    assert!(BlockId::parse(&format!("{}.meta", block_id)).is_err());
+    assert!(BlockId::parse(&format!("{}/../x", block_id)).is_err());
+    assert!(BlockId::parse("STORAGE_ID").is_err());
+}
 
-    async fn put_data(&self, request: Request<PutDataRequest>) -> Result<Response<PutDataResponse>, Status> {
-        let req = request.into_inner();
-        let file_path = Path::new(&self.data_dir).join(req.block_id);
-        let mut file = OpenOptions::new().create(true).write(true).open(file_path).map_err(|e| Status::internal(format!("Failed to create file: {}", e)))?;
-        file.write_all(&req.data).map_err(|e| Status::internal(format!("Failed to write file: {}", e)))?;
-        file.flush().map_err(|e| Status::internal(format!("Failed to flush file: {}", e)))?;
-        // Forward data to the next data node for replication
-        for node in req.nodes_left {
-            // Implement forwarding logic here
-        }
-        Ok(Response::new(PutDataResponse { success: true }))
+#[tokio::test]
+async fn blocks_left_half_written_by_a_crash_are_recovered_or_discarded_at_startup() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let stale = BlockId::generate();
+    let put = PutDataRequest { block_id: stale.to_string(), data: b"finalized".to_vec(), nodes_left: vec![], checksums: vec![], gen_stamp: 0 };
+    datanode.put_data(Request::new(put)).await.unwrap();
+
+    // a writer that synced a whole block but crashed before renaming it, one that crashed part way through a chunk,
+    // one rewriting a block that was already finalized, and one that crashed before it synced anything
+    let data = vec![5u8; checksum::BYTES_PER_CHECKSUM * 3];
+    let (whole, torn, unsynced) = (BlockId::generate(), BlockId::generate(), BlockId::generate());
+    for block_id in [&whole, &torn, &stale] {
+        let mut writer = storage::BlockWriter::create(&data_dir, block_id, 0).unwrap();
+        writer.write_packet(&data, &checksum::chunk_checksums(&data)).unwrap();
+        writer.sync().unwrap();
+        std::mem::forget(writer);
     }
+    let mut writer = storage::BlockWriter::create(&data_dir, &unsynced, 0).unwrap();
+    writer.write_packet(&data, &checksum::chunk_checksums(&data)).unwrap();
+    std::mem::forget(writer);
+    let torn_path = fs::read_dir(data_dir.join("rbw")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.file_stem().is_some_and(|stem| stem == torn.as_str()))
+        .unwrap();
+    let torn_file = fs::OpenOptions::new().write(true).open(torn_path).unwrap();
+    torn_file.set_len(data.len() as u64 - 100).unwrap();
+    assert!(!block_file(&data_dir, whole.as_str()).exists());
+
+    let recovery = storage::recover(&data_dir).unwrap();
+    assert_eq!(recovery, storage::Recovery { migrated: 0, recovered: 1, discarded: 3 });
+    let mut finalized = vec![stale.clone(), whole.clone()];
+    finalized.sort();
+    assert_eq!(dnlib::list_blocks(&data_dir).into_iter().map(|(block_id, _)| block_id).collect::<Vec<_>>(), finalized);
+    assert_eq!(fs::read(block_file(&data_dir, stale.as_str())).unwrap(), b"finalized");
+    assert_eq!(fs::read_dir(data_dir.join("rbw")).unwrap().count(), 0);
+    let get = GetDataRequest { filename: whole.to_string(), offset: 0, length: 0, gen_stamp: 0 };
+    assert_eq!(datanode.get_data(Request::new(get)).await.unwrap().into_inner().data, data);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[test]
+fn writers_of_the_same_block_keep_to_their_own_files() {
+    let data_dir = temp_data_dir();
+    let block_id = BlockId::generate();
+    let (first, second) = (b"first version", b"second version");
+    let mut writer = storage::BlockWriter::create(&data_dir, &block_id, 1).unwrap();
+    writer.write_packet(first, &checksum::chunk_checksums(first)).unwrap();
+
+    // a second writer of the block that gives up removes its own files only, the first one still finishes
+    let mut abandoned = storage::BlockWriter::create(&data_dir, &block_id, 2).unwrap();
+    abandoned.write_packet(second, &checksum::chunk_checksums(second)).unwrap();
+    drop(abandoned);
+    assert_eq!(writer.finish().unwrap(), first.len() as u64);
+    assert_eq!(fs::read(block_file(&data_dir, block_id.as_str())).unwrap(), first);
+    assert_eq!(storage::read_gen_stamp(&data_dir, &block_id).unwrap(), 1);
+    assert_eq!(fs::read_dir(data_dir.join("rbw")).unwrap().count(), 0);
+    fs::remove_dir_all(data_dir).unwrap();
+}
 
-    async fn replication_passthrough(&self, request: Request<ReplicationPassthroughRequest>) -> Result<Response<ReplicationPassthroughResponse>, Status> {
-        let req = request.into_inner();
-        if req.data.is_empty() {
-            return Ok(Response::new(ReplicationPassthroughResponse { success: false }));
-        }
-        // Implement replication passthrough logic here
-        Ok(Response::new(ReplicationPassthroughResponse { success: true }))
+#[test]
+fn deletes_of_an_old_version_never_tear_a_newer_one_being_finalized() {
+    let data_dir = temp_data_dir();
+    let block_id = BlockId::generate();
+    let data = b"newer version";
+    for gen_stamp in 1..100u64 {
+        storage::write_block(&data_dir, &block_id, gen_stamp, data, &checksum::chunk_checksums(data)).unwrap();
+        let writer = {
+            let (data_dir, block_id) = (data_dir.clone(), block_id.clone());
+            std::thread::spawn(move || storage::write_block(&data_dir, &block_id, gen_stamp + 1, data, &checksum::chunk_checksums(data)).unwrap())
+        };
+        let deletion = storage::delete_block(&data_dir, &block_id, gen_stamp).unwrap();
+        writer.join().unwrap();
+        // the delete either went first and the newer version was finalized after it, or it found the newer version and kept it
+        assert!(matches!(deletion, storage::Deletion::Deleted | storage::Deletion::Newer(_)));
+        assert!(block_file(&data_dir, block_id.as_str()).exists());
+        assert!(meta_file(&data_dir, block_id.as_str()).exists());
+        assert_eq!(storage::read_gen_stamp(&data_dir, &block_id).unwrap(), gen_stamp + 1);
     }
+    assert_eq!(storage::delete_block(&data_dir, &block_id, 100).unwrap(), storage::Deletion::Deleted);
+    assert_eq!(storage::delete_block(&data_dir, &block_id, 100).unwrap(), storage::Deletion::Missing);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn put_data_keeps_the_local_replica_and_acks_a_failed_downstream_node() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    // nothing listens on port 1, and the node behind it is never reached
+    let nodes_left = vec!["127.0.0.1:1".to_string(), "127.0.0.1:2".to_string()];
+    let put = PutDataRequest { block_id: block_id.clone(), data: b"hello world".to_vec(), nodes_left, checksums: vec![], gen_stamp: 0 };
+    let response = datanode.put_data(Request::new(put)).await.unwrap().into_inner();
+    assert!(response.success);
+    assert_eq!(response.acks.len(), 1);
+    assert_eq!((response.acks[0].node.as_str(), response.acks[0].success), ("127.0.0.1:1", false));
+    assert_eq!(fs::read(block_file(&data_dir, &block_id)).unwrap(), b"hello world");
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn reads_of_a_replica_older_than_the_requested_generation_stamp_are_refused() {
+    let data_dir = temp_data_dir();
+    let datanode = DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let block_id = BlockId::generate().to_string();
+    let put = PutDataRequest { block_id: block_id.clone(), data: b"hello world".to_vec(), nodes_left: vec![], checksums: vec![], gen_stamp: 5 };
+    datanode.put_data(Request::new(put)).await.unwrap();
+    assert_eq!(storage::read_gen_stamp(&data_dir, &BlockId::parse(&block_id).unwrap()).unwrap(), 5);
+
+    let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 6 };
+    assert_eq!(datanode.get_data(Request::new(get)).await.unwrap_err().code(), Code::FailedPrecondition);
+    let get = GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 5 };
+    assert_eq!(datanode.get_data(Request::new(get)).await.unwrap().into_inner().data, b"hello world");
+    fs::remove_dir_all(data_dir).unwrap();
 }
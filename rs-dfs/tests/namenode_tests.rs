@@ -0,0 +1,1036 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+use tonic::{Code, Request};
+use rs_dfs::{checksum, datanode, namenode, BlockId};
+#[allow(dead_code)]
+#[path = "../src/prj/namenode/nnlib.rs"]
+mod nnlib;
+#[allow(dead_code)]
+#[path = "../src/prj/namenode/editlog.rs"]
+mod editlog;
+#[allow(dead_code)]
+#[path = "../src/prj/namenode/namespace.rs"]
+mod namespace;
+
+#[path = "../src/prj/namenode/placement.rs"]
+mod placement;
+#[allow(dead_code)]
+#[path = "../src/prj/datanode/dnlib.rs"]
+mod dnlib;
+#[allow(dead_code)]
+#[path = "../src/prj/datanode/storage.rs"]
+mod storage;
+use datanode::data_node_server::{DataNode, DataNodeServer};
+use datanode::GetDataRequest;
+use namenode::name_node_server::{NameNode, NameNodeServer};
+use namenode::{AbandonFileRequest, AssignBlocksForFileRequest, CompleteFileRequest, LocatedBlock, BlockReportRequest, DeleteCommand, DeleteFileRequest, DeleteRequest, GetBlockLocationsRequest, GetFileInfoRequest, HeartbeatRequest, PreadRequest, IncrementalBlockReportRequest, ListDirectoryRequest, MkdirsRequest, ReadFileRequest, RenameRequest, ReplicateCommand, ReportedBlock, ShutdownCommand, WriteFileRequest};
+use namenode::data_node_command::Command;
+use editlog::{EditLog, EditOp};
+use nnlib::{CommandQueue, DataNodeStats, NameNodeService, COMMAND_TIMEOUT_MS, FILE_LEASE_MS, MAX_COMMAND_ATTEMPTS, NameNodeState, ReplicationLimits, SerializableNodeAddress};
+
+fn temp_data_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rs-dfs-nn-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// block_file is where a data node keeps a finalized block
+fn block_file(data_dir: &Path, block_id: &str) -> PathBuf {
+    storage::block_path(data_dir, &BlockId::parse(block_id).unwrap())
+}
+
+// start_data_node serves a DataNodeService from data_dir on an ephemeral local port
+async fn start_data_node(data_dir: &Path) -> SerializableNodeAddress {
+    serve_data_node(dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string())).await
+}
+
+async fn serve_data_node(datanode: dnlib::DataNodeService) -> SerializableNodeAddress {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(
+        Server::builder()
+            .add_service(DataNodeServer::new(datanode))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    SerializableNodeAddress { host: "127.0.0.1".to_string(), port: port as u32 }
+}
+
+async fn start_cluster(num_data_nodes: usize, block_size: u32, repl_factor: u32) -> (NameNodeService, Vec<PathBuf>) {
+    let mut data_dirs = Vec::new();
+    let mut data_nodes = Vec::new();
+    for _ in 0..num_data_nodes {
+        let data_dir = temp_data_dir();
+        data_nodes.push((start_data_node(&data_dir).await, 0));
+        data_dirs.push(data_dir);
+    }
+    let state = NameNodeState::new(block_size, repl_factor, data_nodes);
+    (NameNodeService { state: Arc::new(RwLock::new(state)), ..Default::default() }, data_dirs)
+}
+
+fn write_request(filename: &str, data: &[u8]) -> Request<WriteFileRequest> {
+    Request::new(WriteFileRequest { filename: filename.to_string(), data: data.to_vec(), nodes_left: vec![] })
+}
+
+#[tokio::test]
+async fn write_file_replicates_every_block_through_the_pipeline() {
+    let (namenode, data_dirs) = start_cluster(3, 10, 3).await;
+    let data = b"twenty-five bytes of data".to_vec();
+    assert!(namenode.write_file(write_request("/a.txt", &data)).await.unwrap().into_inner().success);
+
+    let state = namenode.state.read().await;
+    let blocks = &state.namespace.get_file("/a.txt").unwrap().blocks;
+    assert_eq!(blocks.len(), 3);
+    for (block_id, chunk) in blocks.iter().zip(data.chunks(10)) {
+        assert_eq!(state.block_to_data_node_ids[block_id].len(), 3);
+        for data_dir in &data_dirs {
+            assert_eq!(fs::read(block_file(data_dir, block_id)).unwrap(), chunk);
+        }
+    }
+    drop(state);
+
+    let response = namenode.read_file(Request::new(ReadFileRequest { filename: "/a.txt".to_string() })).await.unwrap();
+    assert_eq!(response.into_inner().data, data);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn write_file_rejects_existing_file() {
+    let (namenode, data_dirs) = start_cluster(1, 10, 1).await;
+    namenode.write_file(write_request("/a.txt", b"first")).await.unwrap();
+    let err = namenode.write_file(write_request("/a.txt", b"second")).await.unwrap_err();
+    assert_eq!(err.code(), Code::AlreadyExists);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn write_file_without_data_nodes_records_nothing() {
+    let (namenode, _) = start_cluster(0, 10, 3).await;
+    let err = namenode.write_file(write_request("/a.txt", b"data")).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+    assert!(namenode.state.read().await.namespace.get("/a.txt").is_err());
+}
+
+#[tokio::test]
+async fn zero_block_sizes_are_refused_and_failed_writes_leave_no_blocks_behind() {
+    let (namenode, data_dirs) = start_cluster(2, 0, 2).await;
+    let err = namenode.write_file(write_request("/v.txt", b"data")).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let request = Request::new(AssignBlocksForFileRequest { filename: "/v.txt".to_string(), file_size: 4, client_host: String::new() });
+    assert_eq!(namenode.assign_blocks_for_file(request).await.unwrap_err().code(), Code::InvalidArgument);
+
+    // the path turns into a directory while the blocks are in flight, so the file cannot be recorded
+    namenode.state.write().await.block_size = 10;
+    let (written, _) = tokio::join!(namenode.write_file(write_request("/w.txt", b"twenty-five bytes of data")), async {
+        tokio::task::yield_now().await;
+        namenode.mkdirs(Request::new(MkdirsRequest { path: "/w.txt".to_string() })).await.unwrap();
+    });
+    assert_eq!(written.unwrap_err().code(), Code::AlreadyExists);
+    for data_dir in &data_dirs {
+        assert!(dnlib::list_blocks(data_dir).is_empty());
+    }
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn assign_blocks_for_file_allocates_replica_targets_per_block() {
+    let (namenode, data_dirs) = start_cluster(3, 10, 2).await;
+    let request = Request::new(AssignBlocksForFileRequest { filename: "/b.txt".to_string(), file_size: 25, client_host: String::new() });
+    let blocks = namenode.assign_blocks_for_file(request).await.unwrap().into_inner().blocks;
+    assert_eq!(blocks.len(), 3);
+
+    let state = namenode.state.read().await;
+    let block_ids: Vec<String> = blocks.iter().map(|block| block.block_id.clone()).collect();
+    assert_eq!(state.namespace.get_file("/b.txt").unwrap().blocks, block_ids);
+    for block in &blocks {
+        let node_ids: Vec<String> = block.nodes.iter().map(|node| format!("{}:{}", node.host, node.port)).collect();
+        assert_eq!(node_ids.len(), 2);
+        assert_ne!(node_ids[0], node_ids[1]);
+        // nothing is written yet, so no replica or length is recorded until the data nodes report the block or the file is completed
+        assert!(state.block_to_data_node_ids[&block.block_id].is_empty());
+        assert!(!state.block_lengths.contains_key(&block.block_id));
+        assert!(state.blocks_under_construction.contains(&block.block_id));
+    }
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn assigned_files_are_completed_by_their_client_or_abandoned_when_their_lease_expires() {
+    let (namenode, _) = start_cluster(0, 10, 1).await;
+    let namenode_addr = serve_name_node(&namenode).await;
+    let data_dir = temp_data_dir();
+    let datanode = dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let address = serve_data_node(datanode.clone()).await;
+    datanode.register(&namenode_addr, &address.host, address.port, 1 << 20).await.unwrap();
+    let data = b"fifteen bytes!!";
+    let assign = |filename: &str, file_size: u64| Request::new(AssignBlocksForFileRequest { filename: filename.to_string(), file_size, client_host: String::new() });
+    let info = |path: &str| Request::new(GetFileInfoRequest { path: path.to_string() });
+
+    // until the client completes it the file has no length and cannot be read
+    let blocks = namenode.assign_blocks_for_file(assign("/t.txt", data.len() as u64)).await.unwrap().into_inner().blocks;
+    let file = namenode.get_file_info(info("/t.txt")).await.unwrap().into_inner().info.unwrap();
+    assert!(file.under_construction);
+    assert_eq!((file.length, file.block_count), (0, 2));
+    let request = Request::new(GetBlockLocationsRequest { filename: "/t.txt".to_string(), offset: 0, length: 0 });
+    assert_eq!(namenode.get_block_locations(request).await.unwrap_err().code(), Code::FailedPrecondition);
+
+    // blocks that do not match the assigned ones are refused, the right ones complete the file
+    for block in &blocks {
+        let chunk = &data[block.offset as usize..(block.offset + block.length) as usize];
+        storage::write_block(&data_dir, &BlockId::parse(&block.block_id).unwrap(), block.gen_stamp, chunk, &checksum::chunk_checksums(chunk)).unwrap();
+    }
+    let complete = |blocks: Vec<LocatedBlock>| Request::new(CompleteFileRequest { filename: "/t.txt".to_string(), blocks });
+    assert_eq!(namenode.complete_file(complete(blocks[..1].to_vec())).await.unwrap_err().code(), Code::InvalidArgument);
+    namenode.complete_file(complete(blocks.clone())).await.unwrap();
+    let file = namenode.get_file_info(info("/t.txt")).await.unwrap().into_inner().info.unwrap();
+    assert!(!file.under_construction);
+    assert_eq!(file.length, data.len() as u64);
+    let read = namenode.read_file(Request::new(ReadFileRequest { filename: "/t.txt".to_string() })).await.unwrap().into_inner();
+    assert_eq!(read.data, data);
+    assert_eq!(namenode.complete_file(complete(blocks)).await.unwrap_err().code(), Code::FailedPrecondition);
+    let request = Request::new(AbandonFileRequest { filename: "/t.txt".to_string() });
+    assert_eq!(namenode.abandon_file(request).await.unwrap_err().code(), Code::FailedPrecondition);
+
+    // a client that crashes after writing a block never completes the file, the lease monitor abandons it and the replica is deleted
+    let blocks = namenode.assign_blocks_for_file(assign("/u.txt", 5)).await.unwrap().into_inner().blocks;
+    let block = &blocks[0];
+    storage::write_block(&data_dir, &BlockId::parse(&block.block_id).unwrap(), block.gen_stamp, b"crash", &checksum::chunk_checksums(b"crash")).unwrap();
+    let request = Request::new(IncrementalBlockReportRequest {
+        datanode_id: address.id(),
+        storage_id: dnlib::load_or_create_storage_id(&data_dir).unwrap(),
+        received: vec![ReportedBlock { block_id: block.block_id.clone(), length: 5, gen_stamp: block.gen_stamp }],
+        deleted_block_ids: vec![],
+    });
+    namenode.incremental_block_report(request).await.unwrap();
+    assert_eq!(namenode.state.read().await.block_to_data_node_ids[&block.block_id], vec![address.id()]);
+    let now = nnlib::now_millis();
+    assert_eq!(namenode.abandon_expired_files(now).await.unwrap(), 0);
+    assert_eq!(namenode.abandon_expired_files(now + FILE_LEASE_MS).await.unwrap(), 1);
+    assert_eq!(namenode.get_file_info(info("/u.txt")).await.unwrap_err().code(), Code::NotFound);
+    assert!(!namenode.state.read().await.block_to_data_node_ids.contains_key(&block.block_id));
+    datanode.heartbeat(&namenode_addr).await.unwrap();
+    let mut deleted = false;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        if !block_file(&data_dir, &block.block_id).exists() {
+            deleted = true;
+            break;
+        }
+    }
+    assert!(deleted);
+    assert!(block_file(&data_dir, &namenode.state.read().await.namespace.get_file("/t.txt").unwrap().blocks[0]).exists());
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn get_block_locations_returns_blocks_overlapping_the_range() {
+    let (namenode, data_dirs) = start_cluster(2, 10, 2).await;
+    namenode.write_file(write_request("/c.txt", b"twenty-five bytes of data")).await.unwrap();
+
+    let request = Request::new(GetBlockLocationsRequest { filename: "/c.txt".to_string(), offset: 12, length: 10 });
+    let response = namenode.get_block_locations(request).await.unwrap().into_inner();
+    assert_eq!(response.file_length, 25);
+    let ranges: Vec<(u64, u64)> = response.blocks.iter().map(|block| (block.offset, block.length)).collect();
+    assert_eq!(ranges, vec![(10, 10), (20, 5)]);
+    assert!(response.blocks.iter().all(|block| block.nodes.len() == 2));
+
+    let request = Request::new(GetBlockLocationsRequest { filename: "/c.txt".to_string(), offset: 26, length: 0 });
+    assert_eq!(namenode.get_block_locations(request).await.unwrap_err().code(), Code::OutOfRange);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn edits_replay_on_top_of_the_last_checkpoint() {
+    let (mut namenode, mut data_dirs) = start_cluster(1, 10, 1).await;
+    let state_dir = temp_data_dir();
+    let state_file = state_dir.join("8080.state");
+    let edits_file = state_dir.join("8080.edits");
+    namenode.state_file = Some(state_file.clone());
+    namenode.edit_log = Some(Arc::new(Mutex::new(EditLog::open(&edits_file).unwrap())));
+    namenode.write_file(write_request("/d.txt", b"checkpointed")).await.unwrap();
+    namenode.checkpoint().await.unwrap();
+    assert!(EditLog::read(&edits_file).unwrap().is_empty());
+    namenode.write_file(write_request("/e.txt", b"only in the edit log")).await.unwrap();
+    assert!(namenode.has_pending_edits());
+
+    let mut restored = NameNodeState::load(&state_file).unwrap();
+    assert!(restored.namespace.get("/d.txt").is_ok());
+    assert!(restored.namespace.get("/e.txt").is_err());
+    restored.replay(EditLog::read(&edits_file).unwrap());
+    let state = namenode.state.read().await;
+    assert_eq!(restored.namespace, state.namespace);
+    assert_eq!(restored.block_to_data_node_ids, state.block_to_data_node_ids);
+    assert_eq!(restored.block_lengths, state.block_lengths);
+    assert_eq!(restored.last_txid, state.last_txid);
+
+    // replaying the same log again must not apply anything twice
+    assert_eq!(restored.replay(EditLog::read(&edits_file).unwrap()), 0);
+    data_dirs.push(state_dir);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn directories_can_be_created_listed_and_deleted() {
+    let (namenode, data_dirs) = start_cluster(1, 10, 1).await;
+    let mkdirs = |path: &str| Request::new(MkdirsRequest { path: path.to_string() });
+    let list = |path: &str| Request::new(ListDirectoryRequest { path: path.to_string() });
+    let delete = |path: &str, recursive| Request::new(DeleteRequest { path: path.to_string(), recursive });
+    namenode.mkdirs(mkdirs("/warehouse/2026/10/")).await.unwrap();
+    namenode.write_file(write_request("warehouse//2026/10/part-0", b"row group")).await.unwrap();
+    namenode.write_file(write_request("/warehouse/readme", b"hi")).await.unwrap();
+
+    let entries = namenode.list_directory(list("/warehouse")).await.unwrap().into_inner().entries;
+    let names: Vec<(String, bool, u64)> = entries.into_iter().map(|entry| (entry.name, entry.is_directory, entry.length)).collect();
+    assert_eq!(names, vec![("2026".to_string(), true, 0), ("readme".to_string(), false, 2)]);
+
+    assert_eq!(namenode.mkdirs(mkdirs("/warehouse/readme/x")).await.unwrap_err().code(), Code::FailedPrecondition);
+    assert_eq!(namenode.list_directory(list("/warehouse/../etc")).await.unwrap_err().code(), Code::InvalidArgument);
+    assert_eq!(namenode.delete(delete("/warehouse/2026", false)).await.unwrap_err().code(), Code::FailedPrecondition);
+    assert_eq!(namenode.delete(delete("/", true)).await.unwrap_err().code(), Code::InvalidArgument);
+
+    namenode.delete(delete("/warehouse/2026", true)).await.unwrap();
+    let entries = namenode.list_directory(list("/warehouse")).await.unwrap().into_inner().entries;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(namenode.state.read().await.block_to_data_node_ids.len(), 1);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[test]
+fn placement_skips_full_data_nodes_and_puts_the_writer_first() {
+    let node = |host: &str| SerializableNodeAddress { host: host.to_string(), port: 50010 };
+    let mut state = NameNodeState::new(100, 2, vec![(node("full"), 0), (node("roomy"), 0), (node("writer"), 0)]);
+    state.data_node_capacities.insert(node("full").id(), 1000);
+    state.data_node_stats.insert(node("full").id(), DataNodeStats { capacity: 1000, used: 950, remaining: 50, ..Default::default() });
+    state.data_node_capacities.insert(node("roomy").id(), 1000);
+    for _ in 0..20 {
+        assert_eq!(state.choose_data_nodes(Some("writer")), vec![node("writer"), node("roomy")]);
+        assert_eq!(state.choose_targets(3, &[node("writer").id()]), vec![node("roomy")]);
+    }
+
+    state.placement_policy = "random".parse().unwrap();
+    assert_eq!(state.choose_targets(3, &[]).len(), 3);
+    assert!("round-robin".parse::<placement::PlacementPolicy>().is_err());
+}
+
+#[test]
+fn normalize_path_canonicalises_and_validates_paths() {
+    assert_eq!(namespace::normalize_path("/warehouse//2026/10/").unwrap(), "/warehouse/2026/10");
+    assert_eq!(namespace::normalize_path("warehouse/2026").unwrap(), "/warehouse/2026");
+    assert_eq!(namespace::normalize_path("///").unwrap(), "/");
+    assert!(namespace::normalize_path("").is_err());
+    assert!(namespace::normalize_path("/a/./b").is_err());
+    assert!(namespace::normalize_path("/a/../b").is_err());
+    assert!(namespace::normalize_path("/a/b\n").is_err());
+}
+
+#[tokio::test]
+async fn delete_file_removes_replicas_from_data_nodes() {
+    let (namenode, data_dirs) = start_cluster(2, 10, 2).await;
+    namenode.write_file(write_request("/f.txt", b"twenty-five bytes of data")).await.unwrap();
+    let block_ids = namenode.state.read().await.namespace.get_file("/f.txt").unwrap().blocks.clone();
+    assert!(data_dirs.iter().all(|data_dir| block_file(data_dir, &block_ids[0]).exists()));
+
+    namenode.mkdirs(Request::new(MkdirsRequest { path: "/dir".to_string() })).await.unwrap();
+    let err = namenode.delete_file(Request::new(DeleteFileRequest { filename: "/dir".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+
+    namenode.delete_file(Request::new(DeleteFileRequest { filename: "/f.txt".to_string() })).await.unwrap();
+    let state = namenode.state.read().await;
+    assert!(state.namespace.get("/f.txt").is_err());
+    assert!(state.block_to_data_node_ids.is_empty());
+    for data_dir in &data_dirs {
+        for block_id in &block_ids {
+            assert!(!block_file(data_dir, block_id).exists());
+        }
+    }
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn registered_data_nodes_delete_replicas_of_deleted_files_on_their_next_heartbeat() {
+    let (namenode, _) = start_cluster(0, 10, 1).await;
+    let namenode_addr = serve_name_node(&namenode).await;
+    let data_dir = temp_data_dir();
+    let datanode = dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let address = serve_data_node(datanode.clone()).await;
+    datanode.register(&namenode_addr, &address.host, address.port, 1 << 20).await.unwrap();
+    namenode.write_file(write_request("/g.txt", b"invalidated")).await.unwrap();
+    let block_ids = namenode.state.read().await.namespace.get_file("/g.txt").unwrap().blocks.clone();
+
+    namenode.delete_file(Request::new(DeleteFileRequest { filename: "/g.txt".to_string() })).await.unwrap();
+    assert!(block_ids.iter().all(|block_id| block_file(&data_dir, block_id).exists()));
+    datanode.heartbeat(&namenode_addr).await.unwrap();
+    let mut deleted = false;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        if block_ids.iter().all(|block_id| !block_file(&data_dir, block_id).exists()) {
+            deleted = true;
+            break;
+        }
+    }
+    assert!(deleted);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn rename_moves_inodes_without_touching_blocks() {
+    let (namenode, data_dirs) = start_cluster(1, 10, 1).await;
+    let rename = |src: &str, dst: &str, overwrite| Request::new(RenameRequest { src: src.to_string(), dst: dst.to_string(), overwrite });
+    namenode.mkdirs(Request::new(MkdirsRequest { path: "/staging/job".to_string() })).await.unwrap();
+    namenode.mkdirs(Request::new(MkdirsRequest { path: "/published".to_string() })).await.unwrap();
+    namenode.write_file(write_request("/staging/job/out", b"result")).await.unwrap();
+    namenode.write_file(write_request("/published/old", b"stale")).await.unwrap();
+    let blocks = namenode.state.read().await.namespace.get_file("/staging/job/out").unwrap().blocks.clone();
+
+    assert_eq!(namenode.rename(rename("/missing", "/x", false)).await.unwrap_err().code(), Code::NotFound);
+    assert_eq!(namenode.rename(rename("/staging", "/staging/job/inner", false)).await.unwrap_err().code(), Code::InvalidArgument);
+    assert_eq!(namenode.rename(rename("/staging/job/out", "/published/old", false)).await.unwrap_err().code(), Code::AlreadyExists);
+    assert_eq!(namenode.rename(rename("/staging/job/out", "/nowhere/out", false)).await.unwrap_err().code(), Code::NotFound);
+
+    namenode.rename(rename("/staging/job", "/published/job", false)).await.unwrap();
+    namenode.rename(rename("/published/job/out", "/published/old", true)).await.unwrap();
+    let state = namenode.state.read().await;
+    assert!(state.namespace.get("/staging/job").is_err());
+    assert_eq!(state.namespace.get_file("/published/old").unwrap().blocks, blocks);
+    assert_eq!(state.block_to_data_node_ids.len(), 1);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn get_file_info_reports_file_metadata() {
+    let (namenode, data_dirs) = start_cluster(2, 10, 2).await;
+    let info = |path: &str| Request::new(GetFileInfoRequest { path: path.to_string() });
+    namenode.mkdirs(Request::new(MkdirsRequest { path: "/logs".to_string() })).await.unwrap();
+    namenode.write_file(write_request("/logs/app.log", b"twenty-five bytes of data")).await.unwrap();
+
+    let file = namenode.get_file_info(info("logs/app.log")).await.unwrap().into_inner().info.unwrap();
+    assert_eq!(file.path, "/logs/app.log");
+    assert!(!file.is_directory);
+    assert_eq!((file.length, file.block_size, file.replication, file.block_count), (25, 10, 2, 3));
+    assert!(file.mtime > 0);
+    assert_eq!(file.atime, file.mtime);
+
+    let dir = namenode.get_file_info(info("/logs")).await.unwrap().into_inner().info.unwrap();
+    assert!(dir.is_directory);
+    assert_eq!(dir.mtime, file.mtime);
+
+    namenode.read_file(Request::new(ReadFileRequest { filename: "/logs/app.log".to_string() })).await.unwrap();
+    let read = namenode.get_file_info(info("/logs/app.log")).await.unwrap().into_inner().info.unwrap();
+    // the read falls within the access time precision of the creation, so nothing is logged
+    assert_eq!((read.atime, read.mtime), (file.atime, file.mtime));
+    // once the precision has passed the next read moves the access time on
+    let stale_atime = file.atime - nnlib::ACCESS_TIME_PRECISION_MS;
+    namenode.state.write().await.apply(&EditOp::SetAccessTime { file_name: "/logs/app.log".to_string(), atime: stale_atime }, 0).unwrap();
+    namenode.read_file(Request::new(ReadFileRequest { filename: "/logs/app.log".to_string() })).await.unwrap();
+    let read = namenode.get_file_info(info("/logs/app.log")).await.unwrap().into_inner().info.unwrap();
+    assert!(read.atime >= file.atime);
+    assert_eq!(read.mtime, file.mtime);
+    assert_eq!(namenode.get_file_info(info("/missing")).await.unwrap_err().code(), Code::NotFound);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+// serve_name_node serves the namenode on an ephemeral local port and returns its URL
+async fn serve_name_node(namenode: &NameNodeService) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let namenode_addr = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        Server::builder()
+            .add_service(NameNodeServer::new(namenode.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    namenode_addr
+}
+
+#[tokio::test]
+async fn data_nodes_register_and_keep_replicas_across_address_changes() {
+    let (namenode, _) = start_cluster(0, 10, 1).await;
+    let namenode_addr = serve_name_node(&namenode).await;
+    let data_dir = temp_data_dir();
+    let address = start_data_node(&data_dir).await;
+    let datanode = dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string());
+    let datanode_id = datanode.register(&namenode_addr, &address.host, address.port, 1 << 20).await.unwrap();
+    assert_eq!(datanode_id, address.id());
+    let storage_id = dnlib::load_or_create_storage_id(&data_dir).unwrap();
+    assert!(storage_id.starts_with("DS-"));
+
+    namenode.write_file(write_request("/g.txt", b"registered")).await.unwrap();
+    let mut state = namenode.state.read().await.clone();
+    assert_eq!(state.data_node_storage_ids[&datanode_id], storage_id);
+    assert_eq!(state.data_node_capacities[&datanode_id], 1 << 20);
+
+    // the same storage coming back on another port keeps its replicas
+    let moved = SerializableNodeAddress { host: "127.0.0.1".to_string(), port: address.port + 1 };
+    state.register_data_node(moved.clone(), &storage_id, 1 << 20);
+    assert!(!state.id_to_data_nodes.contains_key(&datanode_id));
+    assert_eq!(state.data_nodes.len(), 1);
+    assert!(state.block_to_data_node_ids.values().all(|ids| *ids == vec![moved.id()]));
+
+    // a fresh storage at that address means the replicas are gone
+    state.register_data_node(moved, "DS-replacement", 1 << 20);
+    assert!(state.block_to_data_node_ids.values().all(|ids| ids.is_empty()));
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn heartbeats_record_last_seen_and_usage() {
+    let (namenode, _) = start_cluster(0, 10, 1).await;
+    let data_node = SerializableNodeAddress { host: "127.0.0.1".to_string(), port: 4210 };
+    namenode.state.write().await.register_data_node(data_node.clone(), "DS-1", 100);
+    let heartbeat = |datanode_id: &str, storage_id: &str| Request::new(HeartbeatRequest {
+        datanode_id: datanode_id.to_string(),
+        storage_id: storage_id.to_string(),
+        capacity: 100,
+        used: 30,
+        remaining: 70,
+        active_transfers: 2,
+        failed_volumes: 0,
+        acks: vec![],
+    });
+
+    assert!(!namenode.heartbeat(heartbeat(&data_node.id(), "DS-1")).await.unwrap().into_inner().reregister);
+    let state = namenode.state.read().await;
+    assert!(state.data_nodes[0].1 > 0);
+    let stats = DataNodeStats { capacity: 100, used: 30, remaining: 70, active_transfers: 2, failed_volumes: 0 };
+    assert_eq!(state.data_node_stats[&data_node.id()], stats);
+    drop(state);
+
+    assert!(namenode.heartbeat(heartbeat("127.0.0.1:9999", "DS-2")).await.unwrap().into_inner().reregister);
+    assert!(namenode.heartbeat(heartbeat(&data_node.id(), "DS-2")).await.unwrap().into_inner().reregister);
+}
+
+#[test]
+fn snapshots_with_the_old_data_node_flag_still_load() {
+    let legacy = r#"{"block_size":10,"repl_factor":1,"data_nodes":[[{"host":"localhost","port":4210},false]],"block_to_data_node_ids":{},"id_to_data_nodes":{}}"#;
+    let state: NameNodeState = serde_json::from_str(legacy).unwrap();
+    assert_eq!(state.data_nodes, vec![(SerializableNodeAddress { host: "localhost".to_string(), port: 4210 }, 0)]);
+}
+
+#[test]
+fn snapshots_with_the_old_flat_file_map_load_into_the_namespace() {
+    let dir = temp_data_dir();
+    let path = dir.join("8080.state");
+    let legacy = r#"{"block_size":10,"repl_factor":2,"data_nodes":[[{"host":"localhost","port":4210},false]],
+        "file_name_to_blocks":{"a.txt":["blk-1"],"/logs/b.txt":["blk-2","blk-3"]},
+        "block_to_data_node_ids":{"blk-1":["localhost:4210"],"blk-2":["localhost:4210"],"blk-3":["localhost:4210"]},
+        "id_to_data_nodes":{"localhost:4210":{"host":"localhost","port":4210}}}"#;
+    fs::write(&path, legacy).unwrap();
+    let state = NameNodeState::load(&path).unwrap();
+    assert_eq!(state.namespace.get_file("/a.txt").unwrap().blocks, vec!["blk-1".to_string()]);
+    let file = state.namespace.get_file("/logs/b.txt").unwrap();
+    assert_eq!(file.blocks, vec!["blk-2".to_string(), "blk-3".to_string()]);
+    assert_eq!((file.replication, file.block_size), (2, 10));
+    assert!(state.namespace.get("/logs").unwrap().is_directory());
+
+    // the migrated namespace is what gets saved from then on
+    state.save(&path).unwrap();
+    assert!(!fs::read_to_string(&path).unwrap().contains("file_name_to_blocks"));
+    assert_eq!(NameNodeState::load(&path).unwrap().namespace, state.namespace);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn silent_data_nodes_are_declared_dead_and_phoenixed() {
+    let (namenode, data_dirs) = start_cluster(2, 10, 2).await;
+    namenode.write_file(write_request("/h.txt", b"two replicas")).await.unwrap();
+    let mut state = namenode.state.write().await;
+    let (silent, _) = state.data_nodes[0].clone();
+    let (never_seen, _) = state.data_nodes[1].clone();
+    state.data_nodes[0].1 = 1_000;
+    assert!(state.dead_data_nodes(5_000, 10_000).is_empty());
+    assert_eq!(state.dead_data_nodes(20_000, 10_000), vec![silent.clone()]);
+    drop(state);
+
+    namenode.phoenix(silent.clone().into()).await.unwrap();
+    let state = namenode.state.read().await;
+    assert!(state.dead_data_nodes(20_000, 10_000).is_empty());
+    assert_eq!(state.choose_data_nodes(None), vec![never_seen.clone()]);
+    assert!(state.block_to_data_node_ids.values().all(|ids| *ids == vec![never_seen.id()]));
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn phoenixing_copies_lost_replicas_to_new_data_nodes() {
+    let (namenode, data_dirs) = start_cluster(3, 10, 2).await;
+    let data = b"fifteen bytes!!".to_vec();
+    namenode.write_file(write_request("/i.txt", &data)).await.unwrap();
+    let state = namenode.state.read().await;
+    let block_ids = state.namespace.get_file("/i.txt").unwrap().blocks.clone();
+    let dead = state.block_to_data_node_ids[&block_ids[0]][0].clone();
+    let dead_node = state.id_to_data_nodes[&dead].clone();
+    let lost_blocks = block_ids.iter().filter(|id| state.block_to_data_node_ids[*id].contains(&dead)).count();
+    drop(state);
+    // the replication monitor is already copying the first block, phoenixing leaves it to the monitor
+    namenode.commands.lock().unwrap().start_copy(&block_ids[0], nnlib::now_millis());
+
+    let result = namenode.phoenix(dead_node.into()).await.unwrap();
+    assert!(result.success, "{}", result.message);
+    assert_eq!(result.outcomes.len(), lost_blocks - 1);
+    assert!(result.outcomes.iter().all(|outcome| outcome.new_nodes.len() == 1 && outcome.block_id != block_ids[0]));
+    // the copies phoenixing made are no longer in flight once their replicas are recorded
+    assert_eq!(namenode.commands.lock().unwrap().blocks_in_flight(nnlib::now_millis()), HashSet::from([block_ids[0].clone()]));
+    let state = namenode.state.read().await;
+    for block_id in &block_ids {
+        let holders = &state.block_to_data_node_ids[block_id];
+        assert_eq!(holders.len(), if *block_id == block_ids[0] { 1 } else { 2 });
+        assert!(!holders.contains(&dead));
+    }
+    drop(state);
+    let response = namenode.read_file(Request::new(ReadFileRequest { filename: "/i.txt".to_string() })).await.unwrap();
+    assert_eq!(response.into_inner().data, data);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn block_reports_reconcile_the_block_map() {
+    let (namenode, data_dirs) = start_cluster(1, 10, 1).await;
+    namenode.write_file(write_request("/j.txt", b"twenty-five bytes of data")).await.unwrap();
+    let mut state = namenode.state.write().await;
+    let (data_node, _) = state.data_nodes[0].clone();
+    state.register_data_node(data_node.clone(), "DS-j", 1 << 20);
+    let block_ids = state.namespace.get_file("/j.txt").unwrap().blocks.clone();
+    let gen_stamps = state.block_gen_stamps.clone();
+    drop(state);
+    let reported = |blocks: Vec<(String, u64)>| blocks.into_iter()
+        .map(|(block_id, length)| ReportedBlock { gen_stamp: gen_stamps.get(&block_id).copied().unwrap_or(0), block_id, length })
+        .collect::<Vec<_>>();
+
+    // one replica lost on disk, one truncated, and a stray block nobody owns
+    fs::remove_file(block_file(&data_dirs[0], &block_ids[0])).unwrap();
+    fs::write(block_file(&data_dirs[0], &block_ids[1]), b"short").unwrap();
+    let stray = BlockId::generate().to_string();
+    fs::write(block_file(&data_dirs[0], &stray), b"stray").unwrap();
+    let request = Request::new(BlockReportRequest {
+        datanode_id: data_node.id(),
+        storage_id: "DS-j".to_string(),
+        blocks: reported(dnlib::list_blocks(&data_dirs[0]).into_iter().map(|(block_id, length)| (block_id.into(), length)).collect()),
+    });
+    let response = namenode.block_report(request).await.unwrap().into_inner();
+    assert!(!response.reregister);
+    let mut missing = vec![block_ids[0].clone(), block_ids[1].clone()];
+    missing.sort();
+    assert_eq!(response.missing_block_ids, missing);
+    assert_eq!(response.orphaned_block_ids, vec![stray.clone()]);
+    assert!(response.extra_block_ids.is_empty());
+    let state = namenode.state.read().await;
+    assert!(state.block_to_data_node_ids[&block_ids[0]].is_empty());
+    assert_eq!(state.block_to_data_node_ids[&block_ids[2]], vec![data_node.id()]);
+    drop(state);
+
+    // the truncated replica is deleted right away, the stray one once the next full report still has it
+    let deleted = |commands: Vec<namenode::DataNodeCommand>| commands.into_iter()
+        .flat_map(|command| match command.command {
+            Some(Command::Delete(delete)) => delete.block_ids,
+            _ => vec![],
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(deleted(namenode.commands.lock().unwrap().take(&data_node.id(), 0)), vec![block_ids[1].clone()]);
+    let request = Request::new(BlockReportRequest {
+        datanode_id: data_node.id(),
+        storage_id: "DS-j".to_string(),
+        blocks: reported(dnlib::list_blocks(&data_dirs[0]).into_iter().map(|(block_id, length)| (block_id.into(), length)).collect()),
+    });
+    namenode.block_report(request).await.unwrap();
+    let mut unwanted = deleted(namenode.commands.lock().unwrap().take(&data_node.id(), 0));
+    unwanted.sort();
+    let mut expected = vec![block_ids[1].clone(), stray.clone()];
+    expected.sort();
+    assert_eq!(unwanted, expected);
+
+    // the replica comes back, then the last one is deleted
+    let request = Request::new(IncrementalBlockReportRequest {
+        datanode_id: data_node.id(),
+        storage_id: "DS-j".to_string(),
+        received: reported(vec![(block_ids[0].clone(), 10)]),
+        deleted_block_ids: vec![block_ids[2].clone()],
+    });
+    assert!(!namenode.incremental_block_report(request).await.unwrap().into_inner().reregister);
+    let state = namenode.state.read().await;
+    assert_eq!(state.block_to_data_node_ids[&block_ids[0]], vec![data_node.id()]);
+    assert!(state.block_to_data_node_ids[&block_ids[2]].is_empty());
+    drop(state);
+
+    // a report naming something that is not a block ID is refused
+    let request = Request::new(IncrementalBlockReportRequest {
+        datanode_id: data_node.id(),
+        storage_id: "DS-j".to_string(),
+        received: vec![],
+        deleted_block_ids: vec!["../STORAGE_ID".to_string()],
+    });
+    assert_eq!(namenode.incremental_block_report(request).await.unwrap_err().code(), Code::InvalidArgument);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn replication_monitor_restores_missing_and_trims_surplus_replicas() {
+    let (namenode, data_dirs) = start_cluster(3, 10, 2).await;
+    namenode.write_file(write_request("/k.txt", b"twenty-five bytes of data")).await.unwrap();
+    let block_ids = namenode.state.read().await.namespace.get_file("/k.txt").unwrap().blocks.clone();
+    let mut state = namenode.state.write().await;
+    for block_id in &block_ids {
+        let data_node_id = state.block_to_data_node_ids[block_id][1].clone();
+        let index = state.data_nodes.iter().position(|(addr, _)| addr.id() == data_node_id).unwrap();
+        fs::remove_file(block_file(&data_dirs[index], block_id)).unwrap();
+        state.apply(&EditOp::RemoveReplica { block_id: block_id.clone(), data_node_id }, 0).unwrap();
+    }
+    drop(state);
+
+    // one block per pass, so the limit holds the rest back
+    let limits = ReplicationLimits { max_replications: 1, max_deletions: 100, max_streams_per_node: 2 };
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (1, 0));
+    let limits = ReplicationLimits { max_replications: 10, ..limits };
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (2, 0));
+    let state = namenode.state.read().await;
+    assert!(block_ids.iter().all(|block_id| state.block_to_data_node_ids[block_id].len() == 2));
+    drop(state);
+
+    namenode.state.write().await.repl_factor = 1;
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (0, 3));
+    let state = namenode.state.read().await;
+    for block_id in &block_ids {
+        assert_eq!(state.block_to_data_node_ids[block_id].len(), 1);
+        assert_eq!(data_dirs.iter().filter(|data_dir| block_file(data_dir, block_id).exists()).count(), 1);
+    }
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn heartbeat_responses_carry_commands_that_are_acknowledged() {
+    let (namenode, _) = start_cluster(0, 10, 1).await;
+    let namenode_addr = serve_name_node(&namenode).await;
+    let mut data_dirs = Vec::new();
+    let mut datanodes = Vec::new();
+    for _ in 0..2 {
+        let data_dir = temp_data_dir();
+        let datanode = dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string());
+        let address = serve_data_node(datanode.clone()).await;
+        datanode.register(&namenode_addr, &address.host, address.port, 1 << 20).await.unwrap();
+        data_dirs.push(data_dir);
+        datanodes.push((datanode, address));
+    }
+    namenode.write_file(write_request("/l.txt", b"commanded")).await.unwrap();
+    let block_id = namenode.state.read().await.namespace.get_file("/l.txt").unwrap().blocks[0].clone();
+    let source = namenode.state.read().await.block_to_data_node_ids[&block_id][0].clone();
+    let (source_node, target_node) = if datanodes[0].1.id() == source { (&datanodes[0], &datanodes[1]) } else { (&datanodes[1], &datanodes[0]) };
+
+    // the copy is handed to the source on its next heartbeat and recorded once it is acknowledged
+    namenode.state.write().await.repl_factor = 2;
+    let limits = ReplicationLimits { max_replications: 10, max_deletions: 100, max_streams_per_node: 2 };
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (1, 0));
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (0, 0));
+    assert!(!source_node.0.heartbeat(&namenode_addr).await.unwrap());
+    let mut copied = false;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        source_node.0.heartbeat(&namenode_addr).await.unwrap();
+        if namenode.state.read().await.block_to_data_node_ids[&block_id].len() == 2 {
+            copied = true;
+            break;
+        }
+    }
+    assert!(copied);
+    assert!(namenode.state.read().await.block_to_data_node_ids[&block_id].contains(&target_node.1.id()));
+
+    namenode.commands.lock().unwrap().queue(&target_node.1.id(), Command::Shutdown(ShutdownCommand {}));
+    target_node.0.heartbeat(&namenode_addr).await.unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(1), target_node.0.shutdown_requested()).await.unwrap();
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[test]
+fn unacknowledged_and_failed_commands_are_retried_or_dropped() {
+    let mut queue = CommandQueue::default();
+    let replicate = queue.queue("a:1", Command::Replicate(ReplicateCommand { block_id: "b1".to_string(), targets: vec!["b:2".to_string()], gen_stamp: 1 }));
+    let delete = queue.queue("a:1", Command::Delete(DeleteCommand { block_ids: vec!["b2".to_string()], gen_stamp: 1 }));
+    assert_eq!(queue.take("a:1", 0).len(), 2);
+    assert!(queue.blocks_in_flight(0).contains("b1"));
+
+    // a failed copy is no longer in flight, a failed delete goes out again on the next heartbeat
+    queue.retry("a:1", replicate);
+    queue.retry("a:1", delete);
+    assert!(queue.blocks_in_flight(0).is_empty());
+    let commands = queue.take("a:1", 0);
+    assert_eq!(commands.iter().map(|command| command.command_id).collect::<Vec<_>>(), vec![delete]);
+
+    // it went out twice so far, an unacknowledged delete is sent again after the deadline until it was sent MAX_COMMAND_ATTEMPTS times
+    let mut now = 0;
+    for _ in 2..MAX_COMMAND_ATTEMPTS {
+        now += COMMAND_TIMEOUT_MS;
+        assert_eq!(queue.take("a:1", now).len(), 1);
+    }
+    now += COMMAND_TIMEOUT_MS;
+    assert!(queue.take("a:1", now).is_empty());
+    assert!(queue.ack("a:1", delete).is_none());
+
+    // a copy that is never acknowledged frees its block once the deadline passes, a data node that registers again starts afresh
+    queue.queue("a:1", Command::Replicate(ReplicateCommand { block_id: "b3".to_string(), targets: vec![], gen_stamp: 1 }));
+    queue.take("a:1", now);
+    assert!(queue.blocks_in_flight(now + COMMAND_TIMEOUT_MS - 1).contains("b3"));
+    assert!(queue.blocks_in_flight(now + COMMAND_TIMEOUT_MS).is_empty());
+    queue.queue("a:1", Command::Delete(DeleteCommand { block_ids: vec!["b4".to_string()], gen_stamp: 1 }));
+    queue.forget("a:1");
+    assert!(queue.take("a:1", now).is_empty());
+
+    // a copy the namenode makes itself is in flight until it finishes, or until the deadline passes if it never reports back
+    queue.start_copy("b5", now);
+    assert!(queue.blocks_in_flight(now + COMMAND_TIMEOUT_MS - 1).contains("b5"));
+    assert!(queue.blocks_in_flight(now + COMMAND_TIMEOUT_MS).is_empty());
+    queue.finish_copy("b5");
+    assert!(queue.blocks_in_flight(now).is_empty());
+}
+
+#[tokio::test]
+async fn corrupt_replicas_are_reported_forgotten_and_deleted() {
+    let (namenode, _) = start_cluster(0, 10, 2).await;
+    let namenode_addr = serve_name_node(&namenode).await;
+    let mut datanodes = Vec::new();
+    for _ in 0..2 {
+        let data_dir = temp_data_dir();
+        let datanode = dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string());
+        let address = serve_data_node(datanode.clone()).await;
+        datanode.register(&namenode_addr, &address.host, address.port, 1 << 20).await.unwrap();
+        datanodes.push((datanode, address, data_dir));
+    }
+    namenode.write_file(write_request("/m.txt", b"checksums")).await.unwrap();
+    let block_id = namenode.state.read().await.namespace.get_file("/m.txt").unwrap().blocks[0].clone();
+    assert_eq!(namenode.state.read().await.block_to_data_node_ids[&block_id].len(), 2);
+
+    // a read that fails verification gets the replica reported, forgotten and deleted on the next heartbeat
+    let (datanode, _, data_dir) = &datanodes[0];
+    fs::write(block_file(data_dir, &block_id), b"checksumz").unwrap();
+    let status = datanode.get_data(Request::new(GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 })).await.unwrap_err();
+    assert_eq!(status.code(), Code::DataLoss);
+    assert_eq!(namenode.state.read().await.block_to_data_node_ids[&block_id], vec![datanodes[1].1.id()]);
+    datanode.heartbeat(&namenode_addr).await.unwrap();
+    let mut deleted = false;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        if !block_file(data_dir, &block_id).exists() {
+            deleted = true;
+            break;
+        }
+    }
+    assert!(deleted);
+
+    // the last replica is kept even when it is corrupt
+    let (datanode, _, data_dir) = &datanodes[1];
+    fs::write(block_file(data_dir, &block_id), b"checksumz").unwrap();
+    datanode.get_data(Request::new(GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp: 0 })).await.unwrap_err();
+    assert_eq!(namenode.state.read().await.block_to_data_node_ids[&block_id].len(), 1);
+    for (_, _, data_dir) in datanodes {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn stale_replicas_are_refused_reported_and_deleted() {
+    let (namenode, _) = start_cluster(0, 10, 2).await;
+    let namenode_addr = serve_name_node(&namenode).await;
+    let mut datanodes = Vec::new();
+    for _ in 0..2 {
+        let data_dir = temp_data_dir();
+        let datanode = dnlib::DataNodeService::new(data_dir.to_string_lossy().to_string());
+        let address = serve_data_node(datanode.clone()).await;
+        datanode.register(&namenode_addr, &address.host, address.port, 1 << 20).await.unwrap();
+        datanodes.push((datanode, address, data_dir));
+    }
+    namenode.write_file(write_request("/s.txt", b"version 2")).await.unwrap();
+    let state = namenode.state.read().await;
+    let block_id = state.namespace.get_file("/s.txt").unwrap().blocks[0].clone();
+    let gen_stamp = state.gen_stamp(&block_id);
+    assert!(gen_stamp > 0);
+    drop(state);
+
+    // the first data node holds on to an older version of the block, as if it dropped out of the pipeline that wrote this one
+    let (datanode, _, data_dir) = &datanodes[0];
+    let old = b"version 1";
+    storage::write_block(data_dir, &BlockId::parse(&block_id).unwrap(), gen_stamp - 1, old, &checksum::chunk_checksums(old)).unwrap();
+    let status = datanode.get_data(Request::new(GetDataRequest { filename: block_id.clone(), offset: 0, length: 0, gen_stamp })).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let located = namenode.get_block_locations(Request::new(GetBlockLocationsRequest { filename: "/s.txt".to_string(), offset: 0, length: 0 }))
+        .await.unwrap().into_inner();
+    assert_eq!(located.blocks[0].gen_stamp, gen_stamp);
+
+    // its block report gets the replica forgotten and deleted on the next heartbeat
+    datanode.block_report(&namenode_addr).await.unwrap();
+    assert_eq!(namenode.state.read().await.block_to_data_node_ids[&block_id], vec![datanodes[1].1.id()]);
+    datanode.heartbeat(&namenode_addr).await.unwrap();
+    let mut deleted = false;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        if !block_file(data_dir, &block_id).exists() {
+            deleted = true;
+            break;
+        }
+    }
+    assert!(deleted);
+
+    // a delete meant for the stale replica leaves alone the current version the data node got since
+    storage::write_block(data_dir, &BlockId::parse(&block_id).unwrap(), gen_stamp - 1, old, &checksum::chunk_checksums(old)).unwrap();
+    datanode.block_report(&namenode_addr).await.unwrap();
+    let current = b"version 2";
+    storage::write_block(data_dir, &BlockId::parse(&block_id).unwrap(), gen_stamp, current, &checksum::chunk_checksums(current)).unwrap();
+    datanode.heartbeat(&namenode_addr).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(fs::read(block_file(data_dir, &block_id)).unwrap(), current);
+
+    let response = namenode.read_file(Request::new(ReadFileRequest { filename: "/s.txt".to_string() })).await.unwrap();
+    assert_eq!(response.into_inner().data, b"version 2");
+    for (_, _, data_dir) in datanodes {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn blocks_larger_than_a_grpc_message_are_streamed_in_packets() {
+    let block_size = 6 << 20;
+    let (namenode, data_dirs) = start_cluster(2, block_size, 1).await;
+    let data: Vec<u8> = (0..block_size as usize - 100).map(|i| (i % 251) as u8).collect();
+    namenode.write_file(write_request("/big.bin", &data)).await.unwrap();
+    let read = namenode.read_file(Request::new(ReadFileRequest { filename: "/big.bin".to_string() })).await.unwrap();
+    assert!(read.into_inner().data == data);
+
+    // the copy streams from one data node to the other as well
+    namenode.state.write().await.repl_factor = 2;
+    let limits = ReplicationLimits { max_replications: 10, max_deletions: 100, max_streams_per_node: 2 };
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (1, 0));
+    let block_id = namenode.state.read().await.namespace.get_file("/big.bin").unwrap().blocks[0].clone();
+    for data_dir in &data_dirs {
+        assert!(fs::read(block_file(data_dir, &block_id)).unwrap() == data);
+    }
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn a_copy_from_a_replica_that_turns_out_corrupt_leaves_nothing_behind() {
+    let block_size = 2 << 20;
+    let (namenode, data_dirs) = start_cluster(2, block_size, 1).await;
+    namenode.write_file(write_request("/rot.bin", &vec![3u8; block_size as usize])).await.unwrap();
+    let block_id = namenode.state.read().await.namespace.get_file("/rot.bin").unwrap().blocks[0].clone();
+    let (source, target) = if block_file(&data_dirs[0], &block_id).exists() { (&data_dirs[0], &data_dirs[1]) } else { (&data_dirs[1], &data_dirs[0]) };
+
+    // the first packets go through before the corrupt tail is read
+    let mut corrupt = vec![3u8; block_size as usize];
+    *corrupt.last_mut().unwrap() = 4;
+    fs::write(block_file(source, &block_id), corrupt).unwrap();
+    namenode.state.write().await.repl_factor = 2;
+    let limits = ReplicationLimits { max_replications: 10, max_deletions: 100, max_streams_per_node: 2 };
+    assert_eq!(namenode.replicate_and_trim(&limits).await.unwrap(), (0, 0));
+    let mut cleaned_up = false;
+    for _ in 0..50 {
+        if !block_file(target, &block_id).exists() {
+            cleaned_up = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(cleaned_up);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn read_file_falls_back_to_other_replicas_and_fails_on_unreadable_blocks() {
+    let (namenode, data_dirs) = start_cluster(2, 10, 2).await;
+    namenode.write_file(write_request("/r.txt", b"twenty-five bytes of data")).await.unwrap();
+    let read = || Request::new(ReadFileRequest { filename: "/r.txt".to_string() });
+    let block_ids = namenode.state.read().await.namespace.get_file("/r.txt").unwrap().blocks.clone();
+
+    // the first replica of every block is gone, the second one is read instead
+    for block_id in &block_ids {
+        let first = namenode.state.read().await.block_to_data_node_ids[block_id][0].clone();
+        let index = namenode.state.read().await.data_nodes.iter().position(|(node, _)| node.id() == first).unwrap();
+        fs::remove_file(block_file(&data_dirs[index], block_id)).unwrap();
+    }
+    assert_eq!(namenode.read_file(read()).await.unwrap().into_inner().data, b"twenty-five bytes of data");
+
+    // a block without any replica fails the read instead of leaving a hole in the data
+    namenode.state.write().await.block_to_data_node_ids.get_mut(&block_ids[1]).unwrap().clear();
+    assert_eq!(namenode.read_file(read()).await.unwrap_err().code(), Code::Unavailable);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn pread_reads_a_range_across_block_boundaries() {
+    let (namenode, data_dirs) = start_cluster(2, 2000, 1).await;
+    let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    namenode.write_file(write_request("/cols.bin", &data)).await.unwrap();
+    let pread = |offset, length| namenode.pread(Request::new(PreadRequest { filename: "/cols.bin".to_string(), offset, length }));
+
+    assert_eq!(pread(1500, 1200).await.unwrap().into_inner().data, data[1500..2700]);
+    assert_eq!(pread(3999, 2).await.unwrap().into_inner().data, data[3999..4001]);
+    // a footer read that runs past the end comes back short
+    assert_eq!(pread(4900, 500).await.unwrap().into_inner().data, data[4900..]);
+    assert_eq!(pread(4000, 0).await.unwrap().into_inner().data, data[4000..]);
+    assert_eq!(pread(5001, 1).await.unwrap_err().code(), Code::OutOfRange);
+    for data_dir in data_dirs {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn writes_rebuild_the_pipeline_around_a_failed_data_node() {
+    let (namenode, data_dirs) = start_cluster(4, 10, 3).await;
+    // the broken data node answers but cannot store anything, its data directory is a file
+    fs::remove_dir_all(&data_dirs[0]).unwrap();
+    fs::write(&data_dirs[0], b"not a directory").unwrap();
+    let broken = namenode.state.read().await.data_nodes[0].0.id();
+
+    let data: Vec<u8> = (0..100).map(|i| i as u8).collect();
+    assert!(namenode.write_file(write_request("/n.txt", &data)).await.unwrap().into_inner().success);
+    let state = namenode.state.read().await;
+    for block_id in &state.namespace.get_file("/n.txt").unwrap().blocks {
+        let replicas = &state.block_to_data_node_ids[block_id];
+        assert_eq!(replicas.len(), 3);
+        assert!(!replicas.contains(&broken));
+        for (index, data_dir) in data_dirs.iter().enumerate().skip(1) {
+            let data_node_id = state.data_nodes[index].0.id();
+            assert_eq!(block_file(data_dir, block_id).exists(), replicas.contains(&data_node_id));
+        }
+    }
+    drop(state);
+    let read = namenode.read_file(Request::new(ReadFileRequest { filename: "/n.txt".to_string() })).await.unwrap();
+    assert_eq!(read.into_inner().data, data);
+    fs::remove_file(&data_dirs[0]).unwrap();
+    for data_dir in &data_dirs[1..] {
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}